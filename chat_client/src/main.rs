@@ -3,7 +3,7 @@
 // “Network Applications and Design” Homework Assignment #4
 
 use std::env;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
 use std::net::TcpStream;
 use std::process;
 use std::sync::{Arc, Mutex};
@@ -22,16 +22,87 @@ const CMD_BAN: u8 = 4;
 const CMD_PING: u8 = 5;
 const CMD_EXIT: u8 = 6;
 const CMD_CHAT: u8 = 7;
+const CMD_OPER: u8 = 8;
+const CMD_VOTEKICK: u8 = 9;
+// 10 is b'\n', the frame terminator, so it is never a command
+const CMD_AUDIT: u8 = 11;
+const CMD_REPORT: u8 = 12;
+const CMD_REPORTS: u8 = 13;
+const CMD_SHADOWBAN: u8 = 14;
+
+// Event codes - 1 byte prefix for server frames that are not plain text
+const EVT_POLICY: u8 = 16;
+// text written by a user, any line without it comes from the server itself
+const EVT_MESSAGE: u8 = 17;
+// the room is full and the server holds the connection in its waiting queue
+const EVT_QUEUE: u8 = 18;
+// a join challenge, answered with a plain line before the welcome message
+const EVT_CHALLENGE: u8 = 19;
 
 // Define a struct to hold client state
 struct ClientState {
     connected: bool,
     nickname: String,
+    // filter policy advertised by the server
+    policy_version: String,
+    prohibited_phrases: Vec<String>,
 }
 
-// Helper function to check for prohibited message content
-fn contains_prohibited_content(content: &str) -> bool {
-    content.to_lowercase().contains("i hate professor")
+impl ClientState {
+    // Helper function to check for prohibited message content
+    fn contains_prohibited_content(&self, content: &str) -> bool {
+        let content = content.to_lowercase();
+        self.prohibited_phrases
+            .iter()
+            .any(|phrase| content.contains(phrase.as_str()))
+    }
+
+    // apply a policy frame: "<version> <phrase>\t<phrase>...", returns true if it changed
+    fn apply_policy(&mut self, payload: &str) -> bool {
+        let (version, phrases) = payload.split_once(' ').unwrap_or((payload, ""));
+        if version == self.policy_version {
+            return false;
+        }
+
+        self.policy_version = version.to_string();
+        self.prohibited_phrases = phrases
+            .split('\t')
+            .filter(|phrase| !phrase.is_empty())
+            .map(|phrase| phrase.to_lowercase())
+            .collect();
+        true
+    }
+}
+
+// Drop control characters so received text cannot drive the terminal
+fn strip_control(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .filter(|c| !matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}'))
+        .collect()
+}
+
+// Server notices are marked so users cannot pass their text off as one
+fn print_system(text: &str) {
+    let text = strip_control(text);
+    if io::stdout().is_terminal() {
+        println!("\x1b[1;36m* {}\x1b[0m", text);
+    } else {
+        println!("* {}", text);
+    }
+}
+
+// Show the server's join challenge and send back what the user types
+fn answer_challenge(mut stream: &TcpStream, prompt: &str) -> io::Result<()> {
+    print_system(prompt);
+    print!("> ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    stream.write_all(answer.trim().as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.flush()
 }
 
 // Function to handle incoming messages from the server
@@ -52,7 +123,22 @@ fn receive_messages(stream: TcpStream, state: Arc<Mutex<ClientState>>) {
                 process::exit(0); // immadiately exit
             }
             Ok(_) => {
-                // Print the received message without adding a newline
+                // the server pushes a new policy when its rules change
+                if line.as_bytes()[0] == EVT_POLICY {
+                    if state.lock().unwrap().apply_policy(line[1..].trim_end()) {
+                        println!("[The server's message filter was updated.]");
+                    }
+                    continue;
+                }
+
+                // messages from other users are printed as they are
+                if line.as_bytes()[0] == EVT_MESSAGE {
+                    println!("{}", strip_control(&line[1..]));
+                    let _ = io::stdout().flush();
+                    continue;
+                }
+
+                // everything else is a notice from the server
                 let nickname = state.lock().unwrap().nickname.clone();
 
                 if line.contains(&format!("{} left the room", nickname)) {
@@ -60,12 +146,12 @@ fn receive_messages(stream: TcpStream, state: Arc<Mutex<ClientState>>) {
                     continue;
                 }
 
-                println!("{}", line.trim_end());
+                print_system(&line);
 
                 // Flush stdout to ensure the message is displayed immediately
                 let _ = io::stdout().flush();
 
-                // check for specific messages
+                // check for specific messages, only server notices can end the session
                 if line.contains("you are banned by")
                     || line.contains("You sent a prohibited message")
                 {
@@ -101,7 +187,7 @@ fn handle_user_input(
             thread::sleep(Duration::from_millis(500));
 
             // check if the connection is still alive
-            if let Err(_) = check_stream.peer_addr() {
+            if check_stream.peer_addr().is_err() {
                 println!("\nLost connection to server. Terminating.");
                 process::exit(0);
             }
@@ -113,9 +199,10 @@ fn handle_user_input(
             let input = line?;
 
             // Check for prohibited content in any input
-            if contains_prohibited_content(&input) {
+            let prohibited = state.lock().unwrap().contains_prohibited_content(&input);
+            if prohibited {
                 println!(
-                    "Warning: Your message contains a prohibited phrase. You will be disconnected."
+                    "Warning: Your message contains a prohibited phrase. It will be blocked and repeated offenses get you muted or disconnected."
                 );
             }
 
@@ -189,6 +276,66 @@ fn handle_user_input(
                         stream.write_all(b"\n")?;
                         stream.flush()?;
                     }
+                    "\\oper" => {
+                        if parts.len() < 2 {
+                            println!("Usage: \\oper <password>");
+                            continue;
+                        }
+
+                        stream.write_all(&[CMD_OPER])?;
+                        stream.write_all(parts[1].as_bytes())?;
+                        stream.write_all(b"\n")?;
+                        stream.flush()?;
+                    }
+                    "\\votekick" => {
+                        if parts.len() < 2 {
+                            println!("Usage: \\votekick <nickname>");
+                            continue;
+                        }
+
+                        let target = parts[1].trim();
+                        stream.write_all(&[CMD_VOTEKICK])?;
+                        stream.write_all(target.as_bytes())?;
+                        stream.write_all(b"\n")?;
+                        stream.flush()?;
+                    }
+                    "\\audit" => {
+                        // optional nickname and entry count
+                        let args = parts.get(1).unwrap_or(&"").trim();
+                        stream.write_all(&[CMD_AUDIT])?;
+                        stream.write_all(args.as_bytes())?;
+                        stream.write_all(b"\n")?;
+                        stream.flush()?;
+                    }
+                    "\\report" => {
+                        let nick_reason: Vec<&str> =
+                            parts.get(1).unwrap_or(&"").splitn(2, ' ').collect();
+                        if nick_reason.len() < 2 || nick_reason[1].trim().is_empty() {
+                            println!("Usage: \\report <nickname> <reason>");
+                            continue;
+                        }
+
+                        stream.write_all(&[CMD_REPORT])?;
+                        stream.write_all(parts[1].as_bytes())?;
+                        stream.write_all(b"\n")?;
+                        stream.flush()?;
+                    }
+                    "\\reports" => {
+                        // list, "show <id>" or "resolve <id> <action>"
+                        let args = parts.get(1).unwrap_or(&"").trim();
+                        stream.write_all(&[CMD_REPORTS])?;
+                        stream.write_all(args.as_bytes())?;
+                        stream.write_all(b"\n")?;
+                        stream.flush()?;
+                    }
+                    "\\shadowban" => {
+                        // list, "<nickname>" or "<nickname> off"
+                        let args = parts.get(1).unwrap_or(&"").trim();
+                        stream.write_all(&[CMD_SHADOWBAN])?;
+                        stream.write_all(args.as_bytes())?;
+                        stream.write_all(b"\n")?;
+                        stream.flush()?;
+                    }
                     "\\ping" => {
                         let start = Instant::now();
 
@@ -222,7 +369,7 @@ fn handle_user_input(
                     }
 
                     // Check for prohibited content
-                    if contains_prohibited_content(&input) {
+                    if prohibited {
                         thread::sleep(Duration::from_millis(500));
                     }
                 }
@@ -284,25 +431,39 @@ fn main() -> io::Result<()> {
             stream_clone.write_all(b"\n")?;
             stream_clone.flush()?;
 
-            // Read the initial response
+            // Create shared state for the client
+            let state = Arc::new(Mutex::new(ClientState {
+                connected: true,
+                nickname: nickname.clone(),
+                policy_version: String::new(),
+                prohibited_phrases: Vec::new(),
+            }));
+
+            // Read the initial response, the filter policy and queue updates come before the welcome message
             let mut reader = BufReader::new(&stream);
             let mut response = String::new();
-            reader.read_line(&mut response)?;
+            loop {
+                response.clear();
+                reader.read_line(&mut response)?;
+
+                match response.as_bytes().first() {
+                    Some(&EVT_POLICY) => {
+                        state.lock().unwrap().apply_policy(response[1..].trim_end());
+                    }
+                    Some(&EVT_QUEUE) => print_system(&response[1..]),
+                    Some(&EVT_CHALLENGE) => answer_challenge(&stream, &response[1..])?,
+                    _ => break,
+                }
+            }
 
             // Check for error responses
             if response.contains("cannot connect") {
-                println!("{}", response.trim());
+                println!("{}", strip_control(response.trim()));
                 process::exit(1);
             }
 
             // Print the welcome message
-            print!("{}", response);
-
-            // Create shared state for the client
-            let state = Arc::new(Mutex::new(ClientState {
-                connected: true,
-                nickname: nickname.clone(),
-            }));
+            print_system(&response);
 
             // Setup Ctrl+C handler
             setup_ctrl_c_handler(stream.try_clone()?);
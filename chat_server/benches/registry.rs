@@ -0,0 +1,272 @@
+// Throughput of the commands that only read the client registry, by number of clients
+//
+//   cargo bench --bench registry
+//
+// Starts the server on a loopback port with rate limits off, connects 1, 2, 4 ...
+// clients and has each one keep a few \ping or \list requests in flight for a
+// while. Requests per second should grow with the clients up to the core count,
+// instead of flattening out behind one lock.
+//
+// The baseline then runs the registry's read side in process: threads building the
+// \list reply from a room of ROOM_SIZE users, once behind a Mutex as the registry
+// used to be and once behind the RwLock it is now.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hint;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Barrier, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PORT: u16 = 20517;
+const CMD_LIST: u8 = 1;
+const CMD_PING: u8 = 5;
+
+// requests each client keeps in flight, so the server never waits on the client
+const WINDOW: usize = 4;
+const MEASURE: Duration = Duration::from_secs(2);
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+// users in the room for the lock baseline
+const ROOM_SIZE: usize = 64;
+
+struct Workload {
+    name: &'static str,
+    cmd: u8,
+    // start of the line that ends one reply
+    reply: &'static str,
+}
+
+const WORKLOADS: [Workload; 2] = [
+    Workload {
+        name: "ping",
+        cmd: CMD_PING,
+        reply: "RTT: ",
+    },
+    Workload {
+        name: "list",
+        cmd: CMD_LIST,
+        reply: "Connected users:",
+    },
+];
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let dir = env::temp_dir().join(format!("chat_server_bench_{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating the bench directory");
+    let config = dir.join("bench.conf");
+    fs::write(
+        &config,
+        config_text(&dir.join("audit.log").display().to_string()),
+    )
+    .expect("writing the bench config");
+
+    // powers of two up to twice the core count
+    let counts: Vec<usize> = (0..)
+        .map(|shift| 1 << shift)
+        .take_while(|&n| n <= (cores * 2).max(2))
+        .collect();
+
+    println!("{} cores, {:?} per run", cores, MEASURE);
+    for workload in &WORKLOADS {
+        println!();
+        println!("{:>8} {:>14} {:>8}", "clients", workload.name, "scaling");
+        let mut base = None;
+        for &clients in &counts {
+            let mut server = start_server(&config.display().to_string());
+            let result = run(workload, clients);
+            let _ = server.kill();
+            let _ = server.wait();
+
+            match result {
+                Ok(rate) => {
+                    let base = *base.get_or_insert(rate);
+                    println!("{:>8} {:>12.0}/s {:>7.2}x", clients, rate, rate / base);
+                }
+                Err(e) => println!("{:>8} failed: {}", clients, e),
+            }
+        }
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+
+    println!();
+    println!(
+        "{:>8} {:>14} {:>14} {:>8}",
+        "threads", "mutex", "rwlock", "ratio"
+    );
+    for &threads in &counts {
+        let mutex = lock_rate(Registry::Mutex(Mutex::new(room())), threads);
+        let rwlock = lock_rate(Registry::RwLock(RwLock::new(room())), threads);
+        println!(
+            "{:>8} {:>12.0}/s {:>12.0}/s {:>7.2}x",
+            threads,
+            mutex,
+            rwlock,
+            rwlock / mutex
+        );
+    }
+}
+
+fn config_text(audit_log: &str) -> String {
+    let mut text = format!("listen = 127.0.0.1:{}\n", PORT);
+    text.push_str(&format!("audit_log_path = {}\n", audit_log));
+    for line in [
+        "metrics_address =",
+        "admin_address =",
+        "log_level = error",
+        "max_clients = 1000",
+        "rate_limit_connection = 0, 0",
+        "rate_limit_list = 0, 0",
+        "rate_limit_ping = 0, 0",
+        "flood_kick_threshold = 0",
+        "shutdown_countdown_secs = 0",
+    ] {
+        text.push_str(line);
+        text.push('\n');
+    }
+    text
+}
+
+fn start_server(config: &str) -> Child {
+    let child = Command::new(env!("CARGO_BIN_EXE_chat_server"))
+        .arg(config)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .expect("starting the server");
+
+    // the port is taken once the server accepts, a probe just leaves again
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+        assert!(Instant::now() < deadline, "the server did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+    child
+}
+
+// requests per second over all clients
+fn run(workload: &'static Workload, clients: usize) -> io::Result<f64> {
+    let barrier = Arc::new(Barrier::new(clients + 1));
+    let threads: Vec<_> = (0..clients)
+        .map(|n| {
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || client(workload, n, &barrier))
+        })
+        .collect();
+
+    // everyone has joined, the room stays the same size while measuring
+    barrier.wait();
+    let started = Instant::now();
+    let mut total = 0;
+    for thread in threads {
+        total += thread.join().expect("client thread panicked")?;
+    }
+    Ok(total as f64 / started.elapsed().as_secs_f64())
+}
+
+fn client(workload: &Workload, n: usize, barrier: &Barrier) -> io::Result<u64> {
+    let joined = join(n);
+    barrier.wait();
+    let stream = joined?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = &stream;
+    let request = [workload.cmd, b'\n'];
+
+    for _ in 0..WINDOW {
+        writer.write_all(&request)?;
+    }
+
+    let deadline = Instant::now() + MEASURE;
+    let mut replies = 0;
+    let mut line = String::new();
+    while Instant::now() < deadline {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if line.starts_with(workload.reply) {
+            replies += 1;
+            writer.write_all(&request)?;
+        }
+    }
+
+    // leave, replies still on the way are not counted
+    writer.write_all(b"\x06\n")?;
+    Ok(replies)
+}
+
+fn join(n: usize) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(("127.0.0.1", PORT))?;
+    stream.set_read_timeout(Some(STARTUP_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    stream.write_all(format!("bench{}\n", n).as_bytes())?;
+    Ok(stream)
+}
+
+// the same registry behind either lock
+enum Registry {
+    Mutex(Mutex<HashMap<String, u16>>),
+    RwLock(RwLock<HashMap<String, u16>>),
+}
+
+impl Registry {
+    fn list(&self) -> String {
+        match self {
+            Registry::Mutex(clients) => listing(&clients.lock().unwrap()),
+            Registry::RwLock(clients) => listing(&clients.read().unwrap()),
+        }
+    }
+}
+
+fn room() -> HashMap<String, u16> {
+    (0..ROOM_SIZE)
+        .map(|n| (format!("bench{}", n), 40000 + n as u16))
+        .collect()
+}
+
+// the \list reply, built while the lock is held as the server does
+fn listing(clients: &HashMap<String, u16>) -> String {
+    let mut reply = String::from("Connected users:\n");
+    for (nickname, port) in clients {
+        reply.push_str(&format!(
+            "{} ({}), 127.0.0.1, {}\n",
+            nickname, nickname, port
+        ));
+    }
+    reply
+}
+
+// \list replies per second over all threads
+fn lock_rate(registry: Registry, threads: usize) -> f64 {
+    let registry = Arc::new(registry);
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let workers: Vec<_> = (0..threads)
+        .map(|_| {
+            let registry = Arc::clone(&registry);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                let deadline = Instant::now() + MEASURE;
+                let mut replies = 0u64;
+                while Instant::now() < deadline {
+                    hint::black_box(registry.list());
+                    replies += 1;
+                }
+                replies
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let started = Instant::now();
+    let total: u64 = workers
+        .into_iter()
+        .map(|worker| worker.join().expect("lock thread panicked"))
+        .sum();
+    total as f64 / started.elapsed().as_secs_f64()
+}
@@ -0,0 +1,109 @@
+// Remote admin listener: the console commands over a token-authenticated connection
+//
+// The client sends the token on the first line and then one console command per
+// line. Each reply is the command's output followed by a line holding a single
+// ".", output lines starting with "." get another one in front (as in SMTP).
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::logging::{log_info, log_warn};
+use crate::net::Listener;
+use crate::{console, Server};
+
+// an idle admin connection is closed after this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// start accepting admin connections on "host:port" or "unix:/path"
+pub fn listen(server: Arc<Server>, address: &str) -> io::Result<()> {
+    let listener = Listener::bind(address)?;
+    let local = matches!(listener, Listener::Unix(..));
+    thread::spawn(move || loop {
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                log_warn!("Admin connection failed: {}", e);
+                continue;
+            }
+        };
+        let _ = stream.set_read_timeout(Some(IDLE_TIMEOUT));
+        let peer = if local {
+            "unix socket".to_string()
+        } else {
+            stream.peer_addr().to_string()
+        };
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Ok(reader) = stream.try_clone() {
+                serve(&server, reader, &stream, &peer);
+            }
+        });
+    });
+
+    log_info!("Admin listener on {}", address);
+    Ok(())
+}
+
+// authenticate one admin connection and run its commands until it closes
+fn serve<R: Read, W: Write>(server: &Arc<Server>, reader: R, mut writer: W, peer: &str) {
+    let mut lines = BufReader::new(reader).lines();
+
+    let token = match lines.next() {
+        Some(Ok(token)) => token,
+        _ => return,
+    };
+    // the token is read from the live config so a reload can rotate it
+    let expected = server.config().admin_token.clone();
+    if expected.is_empty() || !tokens_match(token.trim().as_bytes(), expected.as_bytes()) {
+        log_warn!("Admin connection from {} rejected: bad token", peer);
+        // slow down guessing
+        thread::sleep(Duration::from_secs(1));
+        let _ = writer.write_all(b"error: bad token\n");
+        return;
+    }
+    if writer.write_all(b"ok\n").is_err() {
+        return;
+    }
+    log_info!("Admin connected from {}", peer);
+
+    for line in lines {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        log_info!("Admin {}: {}", peer, line);
+
+        let mut reply = String::new();
+        for output in console::execute(server, "admin", line).lines() {
+            if output.starts_with('.') {
+                reply.push('.');
+            }
+            reply.push_str(output);
+            reply.push('\n');
+        }
+        reply.push_str(".\n");
+
+        if writer.write_all(reply.as_bytes()).is_err() {
+            break;
+        }
+    }
+    log_info!("Admin from {} disconnected", peer);
+}
+
+// compare without stopping at the first difference
+pub fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    if given.len() != expected.len() {
+        return false;
+    }
+    given
+        .iter()
+        .zip(expected)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
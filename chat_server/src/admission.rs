@@ -0,0 +1,169 @@
+// Waiting queue for clients that arrive while the room is full, and the
+// connections still in their handshake
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::Shutdown;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::net::Stream;
+use crate::send_to;
+
+struct Waiting {
+    ticket: u64,
+    nickname: String,
+    ip: String,
+    // to tell the waiter when the server goes away
+    stream: Stream,
+}
+
+#[derive(Default)]
+struct QueueState {
+    waiting: VecDeque<Waiting>,
+    next_ticket: u64,
+}
+
+// First come, first served. Waiters are woken whenever the queue changes or a
+// slot may have freed up, and poll in between so a missed wakeup only delays them.
+#[derive(Default)]
+pub struct WaitingQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl WaitingQueue {
+    // join the back of the queue, returns the ticket that identifies the waiter
+    pub fn join(&self, nickname: &str, stream: Stream) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_ticket += 1;
+        let ticket = state.next_ticket;
+        state.waiting.push_back(Waiting {
+            ticket,
+            nickname: nickname.to_string(),
+            ip: stream.peer_addr().ip().to_string(),
+            stream,
+        });
+        ticket
+    }
+
+    pub fn leave(&self, ticket: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .waiting
+            .retain(|waiting| waiting.ticket != ticket);
+        self.changed.notify_all();
+    }
+
+    // 1-based place in the queue, None once the ticket left it
+    pub fn position(&self, ticket: u64) -> Option<usize> {
+        self.state
+            .lock()
+            .unwrap()
+            .waiting
+            .iter()
+            .position(|waiting| waiting.ticket == ticket)
+            .map(|idx| idx + 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().waiting.is_empty()
+    }
+
+    pub fn contains_nickname(&self, nickname: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .waiting
+            .iter()
+            .any(|waiting| waiting.nickname == nickname)
+    }
+
+    pub fn count_from_ip(&self, ip: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .waiting
+            .iter()
+            .filter(|waiting| waiting.ip == ip)
+            .count()
+    }
+
+    // block until something changes or the timeout passes
+    pub fn wait(&self, timeout: Duration) {
+        let state = self.state.lock().unwrap();
+        let _ = self.changed.wait_timeout(state, timeout).unwrap();
+    }
+
+    // a slot may have freed up
+    pub fn notify(&self) {
+        self.changed.notify_all();
+    }
+
+    // empty the queue with a last message to everyone in it, returns how many there
+    // were. Their threads find their tickets gone and end.
+    pub fn dismiss_all(&self, message: &str) -> usize {
+        let dismissed: Vec<Waiting> = self.state.lock().unwrap().waiting.drain(..).collect();
+        for waiting in &dismissed {
+            let _ = send_to(&waiting.stream, message);
+            let _ = waiting.stream.shutdown(Shutdown::Write);
+        }
+        self.changed.notify_all();
+        dismissed.len()
+    }
+}
+
+struct Handshake {
+    ip: String,
+    // to tell the client when the server goes away
+    stream: Stream,
+}
+
+// Connections between accept and entering the room or the queue, by connection
+// id. Counted per address before a thread is spent on them.
+#[derive(Default)]
+pub struct Handshakes {
+    open: Mutex<HashMap<u64, Handshake>>,
+}
+
+impl Handshakes {
+    // track a new connection, false if its address has `limit` handshakes going
+    // already (0 is no limit)
+    pub fn start(&self, id: u64, stream: &Stream, limit: usize) -> io::Result<bool> {
+        let ip = stream.peer_addr().ip();
+        let mut open = self.open.lock().unwrap();
+        if limit > 0 && open.values().filter(|open| open.ip == ip).count() >= limit {
+            return Ok(false);
+        }
+        let stream = stream.try_clone()?;
+        open.insert(
+            id,
+            Handshake {
+                ip: ip.to_string(),
+                stream,
+            },
+        );
+        Ok(true)
+    }
+
+    // the connection made it into the room or the queue, or is gone
+    pub fn finish(&self, id: u64) {
+        self.open.lock().unwrap().remove(&id);
+    }
+
+    // a last message to every connection still in its handshake, which is then
+    // closed for writing
+    pub fn dismiss_all(&self, message: &str) -> usize {
+        let dismissed: Vec<Handshake> = self.open.lock().unwrap().drain().map(|(_, h)| h).collect();
+        for handshake in &dismissed {
+            let _ = send_to(&handshake.stream, message);
+            let _ = handshake.stream.shutdown(Shutdown::Write);
+        }
+        dismissed.len()
+    }
+}
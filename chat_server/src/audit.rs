@@ -0,0 +1,153 @@
+// Append-only moderation audit log, one JSON object per line
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::Mutex;
+
+use crate::health::LastError;
+use crate::logging::log_warn;
+use crate::metrics::METRICS;
+use crate::rotate::{self, RotatingFile};
+use crate::timestamp;
+
+// One moderation action: who did what to whom and why
+pub struct AuditEntry<'a> {
+    pub action: &'a str,
+    // nickname, or "server" / "vote" for automatic actions
+    pub actor: &'a str,
+    pub actor_addr: Option<&'a str>,
+    pub target: Option<&'a str>,
+    pub target_addr: Option<&'a str>,
+    pub reason: &'a str,
+}
+
+pub struct AuditLog {
+    // empty when the audit log is disabled
+    path: String,
+    // rotated files kept as path.1 ... path.N
+    keep: usize,
+    current: Mutex<Option<RotatingFile>>,
+    last_error: LastError,
+}
+
+impl AuditLog {
+    pub fn open(path: &str, max_bytes: u64, keep: usize) -> io::Result<AuditLog> {
+        let current = if path.is_empty() {
+            None
+        } else {
+            Some(RotatingFile::open(path, max_bytes, keep)?)
+        };
+
+        Ok(AuditLog {
+            path: path.to_string(),
+            keep,
+            current: Mutex::new(current),
+            last_error: LastError::new(),
+        })
+    }
+
+    // append an entry, failures are reported on the console but never stop the server
+    pub fn record(&self, entry: AuditEntry) {
+        METRICS.moderation(entry.action);
+
+        let mut current = self.current.lock().unwrap();
+        let file = match current.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+
+        let line = format!(
+            "{{\"ts\":{},\"action\":{},\"actor\":{},\"actor_addr\":{},\"target\":{},\"target_addr\":{},\"reason\":{}}}\n",
+            json_string(&timestamp::format_utc(timestamp::unix_now())),
+            json_string(entry.action),
+            json_string(entry.actor),
+            json_option(entry.actor_addr),
+            json_option(entry.target),
+            json_option(entry.target_addr),
+            json_string(entry.reason)
+        );
+
+        let result = file.write(&line);
+        self.last_error.record(&result);
+        if let Err(e) = result {
+            log_warn!("Error writing audit log {}: {}", self.path, e);
+        }
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        let result = match self.current.lock().unwrap().as_ref() {
+            Some(file) => file.sync(),
+            None => Ok(()),
+        };
+        self.last_error.record(&result);
+        result
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.get()
+    }
+
+    // newest `count` entries, optionally only those naming `nickname`, oldest first
+    pub fn query(&self, nickname: Option<&str>, count: usize) -> io::Result<Vec<String>> {
+        if self.path.is_empty() {
+            return Ok(Vec::new());
+        }
+        let needle = nickname.map(json_string);
+
+        // walk from the current file back through the rotated ones
+        let mut found: Vec<String> = Vec::new();
+        for idx in 0..=self.keep {
+            let path = rotate::rotated_path(&self.path, idx);
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            };
+
+            let mut matching: Vec<String> = BufReader::new(file)
+                .lines()
+                .collect::<io::Result<Vec<_>>>()?
+                .into_iter()
+                .filter(|line| match &needle {
+                    Some(needle) => {
+                        line.contains(&format!("\"actor\":{}", needle))
+                            || line.contains(&format!("\"target\":{}", needle))
+                    }
+                    None => true,
+                })
+                .collect();
+
+            matching.append(&mut found);
+            found = matching;
+            if found.len() >= count {
+                break;
+            }
+        }
+
+        let skip = found.len().saturating_sub(count);
+        Ok(found.split_off(skip))
+    }
+}
+
+// quoted JSON string with the required escapes
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".to_string(), json_string)
+}
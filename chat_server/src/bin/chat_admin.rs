@@ -0,0 +1,127 @@
+// Command line client for the chat server's admin listener
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::process;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:20418";
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} [-a host:port|unix:/path] [-t token] [command ...]\n\
+         The token can also be given in CHAT_ADMIN_TOKEN. Without a command,\n\
+         commands are read from stdin, one per line.",
+        program
+    );
+    process::exit(1);
+}
+
+// connect to "host:port" or "unix:/path"
+fn connect(address: &str) -> io::Result<(Box<dyn Read>, Box<dyn Write>)> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        let stream = UnixStream::connect(path)?;
+        Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+    } else {
+        let stream = TcpStream::connect(address)?;
+        Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+    }
+}
+
+// send one command and print its reply, returns false once the server is gone
+fn run_command<R: BufRead>(reader: &mut R, writer: &mut dyn Write, command: &str) -> bool {
+    if writer
+        .write_all(format!("{}\n", command).as_bytes())
+        .is_err()
+    {
+        return false;
+    }
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return false,
+            Ok(_) => {}
+        }
+
+        let text = line.trim_end_matches('\n');
+        if text == "." {
+            return true;
+        }
+        // undo the server's dot-stuffing
+        println!("{}", text.strip_prefix('.').unwrap_or(text));
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut address = DEFAULT_ADDRESS.to_string();
+    let mut token = env::var("CHAT_ADMIN_TOKEN").unwrap_or_default();
+
+    let mut idx = 1;
+    while idx < args.len() {
+        match args[idx].as_str() {
+            "-a" | "-t" if idx + 1 < args.len() => {
+                if args[idx] == "-a" {
+                    address = args[idx + 1].clone();
+                } else {
+                    token = args[idx + 1].clone();
+                }
+                idx += 2;
+            }
+            "-h" | "--help" | "-a" | "-t" => usage(&args[0]),
+            _ => break,
+        }
+    }
+    let command = args[idx..].join(" ");
+
+    if token.is_empty() {
+        eprintln!("No admin token given");
+        usage(&args[0]);
+    }
+
+    let (reader, mut writer) = connect(&address).unwrap_or_else(|e| {
+        eprintln!("Cannot connect to {}: {}", address, e);
+        process::exit(1);
+    });
+    let mut reader = BufReader::new(reader);
+
+    // authenticate
+    let mut reply = String::new();
+    if writer.write_all(format!("{}\n", token).as_bytes()).is_err()
+        || reader.read_line(&mut reply).is_err()
+        || reply.trim() != "ok"
+    {
+        eprintln!("Authentication failed: {}", reply.trim());
+        process::exit(1);
+    }
+
+    if !command.is_empty() {
+        if !run_command(&mut reader, writer.as_mut(), &command) && command != "shutdown" {
+            eprintln!("Connection to the server was lost");
+            process::exit(1);
+        }
+        return;
+    }
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !run_command(&mut reader, writer.as_mut(), line) {
+            if line != "shutdown" {
+                eprintln!("Connection to the server was lost");
+                process::exit(1);
+            }
+            break;
+        }
+    }
+}
@@ -0,0 +1,49 @@
+// Puzzles new nicknames answer during the handshake to keep out scripted bots
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::config::ChallengeKind;
+
+const WORDS: [&str; 12] = [
+    "network", "socket", "packet", "router", "server", "client", "thread", "buffer", "stream",
+    "channel", "protocol", "gateway",
+];
+
+pub struct Challenge {
+    pub prompt: String,
+    answer: String,
+}
+
+impl Challenge {
+    // a fresh puzzle of the given kind, None when challenges are off
+    pub fn new(kind: ChallengeKind) -> Option<Challenge> {
+        match kind {
+            ChallengeKind::Off => None,
+            ChallengeKind::Arithmetic => {
+                let a = random_below(20) + 1;
+                let b = random_below(20) + 1;
+                Some(Challenge {
+                    prompt: format!("What is {} + {}?", a, b),
+                    answer: (a + b).to_string(),
+                })
+            }
+            ChallengeKind::Word => {
+                let word = WORDS[random_below(WORDS.len() as u64) as usize];
+                Some(Challenge {
+                    prompt: format!("Type the word '{}' backwards.", word),
+                    answer: word.chars().rev().collect(),
+                })
+            }
+        }
+    }
+
+    pub fn check(&self, answer: &str) -> bool {
+        answer.trim().eq_ignore_ascii_case(&self.answer)
+    }
+}
+
+// std seeds every RandomState with fresh random keys, which is plenty for a puzzle
+fn random_below(bound: u64) -> u64 {
+    RandomState::new().build_hasher().finish() % bound
+}
@@ -0,0 +1,525 @@
+// Server configuration loaded from a simple "key = value" file
+
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::filter::FilterPolicy;
+
+// token bucket size and refill rate
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_sec: f64,
+}
+
+// What happens to a message caught by the spam heuristics
+#[derive(Clone, Copy, PartialEq)]
+pub enum SpamAction {
+    // block the message and warn the sender
+    Warn,
+    // count it like prohibited content
+    Strike,
+    Mute,
+    Kick,
+}
+
+// Puzzle new nicknames must answer before they join
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChallengeKind {
+    Off,
+    // add two small numbers
+    Arithmetic,
+    // type a word backwards
+    Word,
+}
+
+// How much the server logs, each level includes the ones above it
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    // every received frame, with its content only if log_content is set
+    Debug,
+}
+
+impl LogLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+pub struct Config {
+    // prohibited-content rules, also sent to clients during the handshake
+    pub filter: FilterPolicy,
+    // strikes at which a user sending prohibited content is muted / kicked
+    pub strike_mute_threshold: u32,
+    pub strike_kick_threshold: u32,
+    // how long a strike counts against a user
+    pub strike_decay: Duration,
+    // how long a muted user cannot send chat messages
+    pub mute_duration: Duration,
+    // frame rate limits per connection and per command type
+    pub rate_limit_connection: RateLimit,
+    pub rate_limit_chat: RateLimit,
+    pub rate_limit_to: RateLimit,
+    pub rate_limit_list: RateLimit,
+    pub rate_limit_ping: RateLimit,
+    // throttled frames within the window that get a flooding user kicked, 0 never kicks
+    pub flood_kick_threshold: usize,
+    pub flood_window: Duration,
+    // spam heuristics over each sender's messages within the window, 0 disables a check
+    pub spam_window: Duration,
+    pub spam_repeat_threshold: usize,
+    pub spam_similarity: f64,
+    pub spam_mention_threshold: usize,
+    pub spam_caps_threshold: usize,
+    pub spam_action: SpamAction,
+    // password for \oper, operators are disabled while it is empty, and how many
+    // wrong ones get a connection disconnected (0 never)
+    pub operator_password: String,
+    pub oper_failure_limit: usize,
+    pub ban_requires_operator: bool,
+    // vote kick: how long a vote runs and how much of the room must agree
+    pub votekick_duration: Duration,
+    pub votekick_fraction: f64,
+    pub votekick_min_votes: usize,
+    // moderation audit log, disabled when the path is empty
+    pub audit_log_path: String,
+    // rotate once the log reaches this size, keeping this many old files
+    pub audit_max_bytes: u64,
+    pub audit_keep: usize,
+    // relayed messages kept in memory, and how many of them a report captures
+    pub report_buffer_size: usize,
+    pub report_context: usize,
+    // open reports allowed in the queue, and from any one reporter
+    pub report_max_open: usize,
+    pub report_max_open_per_user: usize,
+    // longest frame a client may send, and how many bad frames get it disconnected (0 never)
+    pub max_frame_bytes: usize,
+    pub frame_violation_limit: usize,
+    // room capacity, per-address cap (0 unlimited) and waiting queue length (0 disables it)
+    pub max_clients: usize,
+    pub max_clients_per_ip: usize,
+    pub waiting_queue_size: usize,
+    // how long a new connection may take to send its nickname, and how many may be
+    // doing that at once from one address (0 unlimited)
+    pub handshake_timeout: Duration,
+    pub max_handshakes_per_ip: usize,
+    // join challenge for every new connection
+    pub join_challenge: ChallengeKind,
+    pub join_challenge_attempts: usize,
+    // where clients connect, "host:port" or "unix:/path", all into the same room
+    pub listen: Vec<String>,
+    // expect a PROXY protocol header from these load balancer addresses, which then
+    // stands in for the socket's address ("local" for the Unix socket)
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<String>,
+    // admin listener, "host:port" or "unix:/path", only started when a token is set
+    pub admin_address: String,
+    pub admin_token: String,
+    // HTTP listener for /metrics, disabled when empty
+    pub metrics_address: String,
+    // server log: level, whether message text may be logged, and the file (stderr when empty)
+    pub log_level: LogLevel,
+    pub log_content: bool,
+    pub log_file: String,
+    pub log_max_bytes: u64,
+    pub log_keep: usize,
+    // room transcripts, one file per day in this directory (off when empty)
+    pub transcript_dir: String,
+    // days of transcripts kept, 0 keeps them all
+    pub transcript_retention_days: u64,
+    // whether \to messages go into the transcript too
+    pub transcript_direct_messages: bool,
+    // on SIGINT / SIGTERM: seconds of warning before the server goes down, and why
+    pub shutdown_countdown: Duration,
+    pub shutdown_message: String,
+    // detach from the terminal and run in the background, as an init script expects
+    pub daemon: bool,
+    // the process id is written here at startup, nothing when empty
+    pub pid_file: String,
+    // shown to every user after the welcome message, nothing when empty
+    pub motd: String,
+    // turned away at the handshake, and removed if already connected when a reload adds them
+    pub banned_addresses: Vec<String>,
+    pub banned_nicknames: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            filter: FilterPolicy::default(),
+            strike_mute_threshold: 2,
+            strike_kick_threshold: 3,
+            strike_decay: Duration::from_secs(600),
+            mute_duration: Duration::from_secs(60),
+            rate_limit_connection: RateLimit {
+                burst: 20,
+                per_sec: 5.0,
+            },
+            rate_limit_chat: RateLimit {
+                burst: 10,
+                per_sec: 2.0,
+            },
+            rate_limit_to: RateLimit {
+                burst: 10,
+                per_sec: 2.0,
+            },
+            rate_limit_list: RateLimit {
+                burst: 3,
+                per_sec: 0.2,
+            },
+            rate_limit_ping: RateLimit {
+                burst: 3,
+                per_sec: 1.0,
+            },
+            flood_kick_threshold: 20,
+            flood_window: Duration::from_secs(10),
+            spam_window: Duration::from_secs(30),
+            spam_repeat_threshold: 3,
+            spam_similarity: 0.9,
+            spam_mention_threshold: 5,
+            spam_caps_threshold: 3,
+            spam_action: SpamAction::Strike,
+            operator_password: String::new(),
+            oper_failure_limit: 3,
+            ban_requires_operator: false,
+            votekick_duration: Duration::from_secs(60),
+            votekick_fraction: 0.5,
+            votekick_min_votes: 2,
+            audit_log_path: "moderation_audit.log".to_string(),
+            audit_max_bytes: 10 * 1024 * 1024,
+            audit_keep: 5,
+            report_buffer_size: 200,
+            report_context: 20,
+            report_max_open: 200,
+            report_max_open_per_user: 3,
+            max_frame_bytes: 4096,
+            frame_violation_limit: 3,
+            max_clients: crate::MAX_CLIENTS,
+            max_clients_per_ip: 0,
+            waiting_queue_size: 0,
+            handshake_timeout: Duration::from_secs(30),
+            max_handshakes_per_ip: 8,
+            join_challenge: ChallengeKind::Off,
+            join_challenge_attempts: 3,
+            listen: vec![format!("0.0.0.0:{}", crate::PORT)],
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            admin_address: "127.0.0.1:20418".to_string(),
+            admin_token: String::new(),
+            metrics_address: "127.0.0.1:20419".to_string(),
+            log_level: LogLevel::Info,
+            log_content: false,
+            log_file: String::new(),
+            log_max_bytes: 10 * 1024 * 1024,
+            log_keep: 5,
+            transcript_dir: String::new(),
+            transcript_retention_days: 30,
+            transcript_direct_messages: false,
+            shutdown_countdown: Duration::from_secs(5),
+            shutdown_message: String::new(),
+            daemon: false,
+            pid_file: String::new(),
+            motd: String::new(),
+            banned_addresses: Vec::new(),
+            banned_nicknames: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    // read and validate a config file
+    pub fn load(path: &str) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // parse "key = value" lines, a line starting with '#' is a comment. A '#' further
+    // on is part of the value, passwords and the MOTD may contain one.
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        for (idx, raw_line) in text.lines().enumerate() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected 'key = value'", idx + 1))?;
+
+            config
+                .set(key.trim(), value.trim())
+                .map_err(|e| format!("line {}: {}", idx + 1, e))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "prohibited_phrases" => self.filter = FilterPolicy::new(parse_list(value)),
+            "strike_mute_threshold" => self.strike_mute_threshold = parse_value(key, value)?,
+            "strike_kick_threshold" => self.strike_kick_threshold = parse_value(key, value)?,
+            "strike_decay_secs" => self.strike_decay = parse_secs(key, value)?,
+            "mute_duration_secs" => self.mute_duration = parse_secs(key, value)?,
+            "rate_limit_connection" => self.rate_limit_connection = parse_rate(key, value)?,
+            "rate_limit_chat" => self.rate_limit_chat = parse_rate(key, value)?,
+            "rate_limit_to" => self.rate_limit_to = parse_rate(key, value)?,
+            "rate_limit_list" => self.rate_limit_list = parse_rate(key, value)?,
+            "rate_limit_ping" => self.rate_limit_ping = parse_rate(key, value)?,
+            "flood_kick_threshold" => self.flood_kick_threshold = parse_value(key, value)?,
+            "flood_window_secs" => self.flood_window = parse_secs(key, value)?,
+            "spam_window_secs" => self.spam_window = parse_secs(key, value)?,
+            "spam_repeat_threshold" => self.spam_repeat_threshold = parse_value(key, value)?,
+            "spam_similarity" => self.spam_similarity = parse_value(key, value)?,
+            "spam_mention_threshold" => self.spam_mention_threshold = parse_value(key, value)?,
+            "spam_caps_threshold" => self.spam_caps_threshold = parse_value(key, value)?,
+            "spam_action" => {
+                self.spam_action = match value {
+                    "warn" => SpamAction::Warn,
+                    "strike" => SpamAction::Strike,
+                    "mute" => SpamAction::Mute,
+                    "kick" => SpamAction::Kick,
+                    _ => return Err(format!("invalid value '{}' for {}", value, key)),
+                }
+            }
+            "operator_password" => self.operator_password = value.to_string(),
+            "oper_failure_limit" => self.oper_failure_limit = parse_value(key, value)?,
+            "ban_requires_operator" => self.ban_requires_operator = parse_value(key, value)?,
+            "votekick_duration_secs" => self.votekick_duration = parse_secs(key, value)?,
+            "votekick_fraction" => self.votekick_fraction = parse_value(key, value)?,
+            "votekick_min_votes" => self.votekick_min_votes = parse_value(key, value)?,
+            "audit_log_path" => self.audit_log_path = value.to_string(),
+            "audit_max_bytes" => self.audit_max_bytes = parse_value(key, value)?,
+            "audit_keep" => self.audit_keep = parse_value(key, value)?,
+            "report_buffer_size" => self.report_buffer_size = parse_value(key, value)?,
+            "report_context" => self.report_context = parse_value(key, value)?,
+            "report_max_open" => self.report_max_open = parse_value(key, value)?,
+            "report_max_open_per_user" => self.report_max_open_per_user = parse_value(key, value)?,
+            "max_frame_bytes" => self.max_frame_bytes = parse_value(key, value)?,
+            "frame_violation_limit" => self.frame_violation_limit = parse_value(key, value)?,
+            "max_clients" => self.max_clients = parse_value(key, value)?,
+            "max_clients_per_ip" => self.max_clients_per_ip = parse_value(key, value)?,
+            "waiting_queue_size" => self.waiting_queue_size = parse_value(key, value)?,
+            "handshake_timeout_secs" => self.handshake_timeout = parse_secs(key, value)?,
+            "max_handshakes_per_ip" => self.max_handshakes_per_ip = parse_value(key, value)?,
+            "join_challenge" => {
+                self.join_challenge = match value {
+                    "off" => ChallengeKind::Off,
+                    "arithmetic" => ChallengeKind::Arithmetic,
+                    "word" => ChallengeKind::Word,
+                    _ => return Err(format!("invalid value '{}' for {}", value, key)),
+                }
+            }
+            "join_challenge_attempts" => self.join_challenge_attempts = parse_value(key, value)?,
+            "listen" => self.listen = parse_list(value),
+            "proxy_protocol" => self.proxy_protocol = parse_value(key, value)?,
+            "trusted_proxies" => self.trusted_proxies = parse_list(value),
+            "admin_address" => self.admin_address = value.to_string(),
+            "admin_token" => self.admin_token = value.to_string(),
+            "metrics_address" => self.metrics_address = value.to_string(),
+            "log_level" => {
+                self.log_level = match value {
+                    "error" => LogLevel::Error,
+                    "warn" => LogLevel::Warn,
+                    "info" => LogLevel::Info,
+                    "debug" => LogLevel::Debug,
+                    _ => return Err(format!("invalid value '{}' for {}", value, key)),
+                }
+            }
+            "log_content" => self.log_content = parse_value(key, value)?,
+            "log_file" => self.log_file = value.to_string(),
+            "log_max_bytes" => self.log_max_bytes = parse_value(key, value)?,
+            "log_keep" => self.log_keep = parse_value(key, value)?,
+            "shutdown_countdown_secs" => self.shutdown_countdown = parse_secs(key, value)?,
+            "shutdown_message" => self.shutdown_message = value.to_string(),
+            "daemon" => self.daemon = parse_value(key, value)?,
+            "pid_file" => self.pid_file = value.to_string(),
+            "motd" => self.motd = value.to_string(),
+            "banned_addresses" => self.banned_addresses = parse_list(value),
+            "banned_nicknames" => self.banned_nicknames = parse_list(value),
+            "transcript_dir" => self.transcript_dir = value.to_string(),
+            "transcript_retention_days" => {
+                self.transcript_retention_days = parse_value(key, value)?
+            }
+            "transcript_direct_messages" => {
+                self.transcript_direct_messages = parse_value(key, value)?
+            }
+            _ => return Err(format!("unknown key '{}'", key)),
+        }
+        Ok(())
+    }
+
+    // settings only read at startup that differ in `other`, a reload cannot apply them
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        let mut check = |key, changed| {
+            if changed {
+                keys.push(key);
+            }
+        };
+        check(
+            "audit_log_path",
+            self.audit_log_path != other.audit_log_path,
+        );
+        check(
+            "audit_max_bytes",
+            self.audit_max_bytes != other.audit_max_bytes,
+        );
+        check("audit_keep", self.audit_keep != other.audit_keep);
+        check(
+            "report_buffer_size",
+            self.report_buffer_size != other.report_buffer_size,
+        );
+        check("listen", self.listen != other.listen);
+        check("admin_address", self.admin_address != other.admin_address);
+        check(
+            "metrics_address",
+            self.metrics_address != other.metrics_address,
+        );
+        check("log_file", self.log_file != other.log_file);
+        check("log_max_bytes", self.log_max_bytes != other.log_max_bytes);
+        check("log_keep", self.log_keep != other.log_keep);
+        check(
+            "transcript_dir",
+            self.transcript_dir != other.transcript_dir,
+        );
+        check(
+            "transcript_retention_days",
+            self.transcript_retention_days != other.transcript_retention_days,
+        );
+        check(
+            "transcript_direct_messages",
+            self.transcript_direct_messages != other.transcript_direct_messages,
+        );
+        check("daemon", self.daemon != other.daemon);
+        check("pid_file", self.pid_file != other.pid_file);
+        keys
+    }
+
+    // whether a connection from `ip` starts with a PROXY protocol header
+    pub fn expects_proxy_header(&self, ip: &str) -> bool {
+        self.proxy_protocol && self.trusted_proxies.iter().any(|proxy| proxy == ip)
+    }
+
+    // whether a user may not be in the room under these settings
+    pub fn is_banned(&self, nickname: &str, ip: &str) -> bool {
+        self.banned_nicknames
+            .iter()
+            .any(|banned| banned == nickname)
+            || self.banned_addresses.iter().any(|banned| banned == ip)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.strike_mute_threshold == 0 || self.strike_kick_threshold == 0 {
+            return Err("strike thresholds must be at least 1".to_string());
+        }
+        if self.strike_mute_threshold > self.strike_kick_threshold {
+            return Err("strike_mute_threshold must not exceed strike_kick_threshold".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.spam_similarity) {
+            return Err("spam_similarity must be between 0 and 1".to_string());
+        }
+        if self.listen.is_empty() {
+            return Err("listen needs at least one address".to_string());
+        }
+        // anyone allowed to send a header could claim any address, bans included
+        if self.proxy_protocol && self.trusted_proxies.is_empty() {
+            return Err("proxy_protocol needs the load balancers in trusted_proxies".to_string());
+        }
+        if self.max_clients == 0 {
+            return Err("max_clients must be at least 1".to_string());
+        }
+        if self.join_challenge_attempts == 0 {
+            return Err("join_challenge_attempts must be at least 1".to_string());
+        }
+        if self.handshake_timeout.is_zero() {
+            return Err("handshake_timeout_secs must be at least 1".to_string());
+        }
+        if self.max_frame_bytes < 2 {
+            return Err("max_frame_bytes must be at least 2".to_string());
+        }
+        if !(self.votekick_fraction > 0.0 && self.votekick_fraction <= 1.0) {
+            return Err("votekick_fraction must be above 0 and at most 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for {}", value, key))
+}
+
+// comma separated, empty entries are dropped
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn parse_secs(key: &str, value: &str) -> Result<Duration, String> {
+    parse_value(key, value).map(Duration::from_secs)
+}
+
+// "burst, per_sec", a burst of 0 disables the limit
+fn parse_rate(key: &str, value: &str) -> Result<RateLimit, String> {
+    let (burst, per_sec) = value
+        .split_once(',')
+        .ok_or_else(|| format!("expected 'burst, per_sec' for {}", key))?;
+    let rate = RateLimit {
+        burst: parse_value(key, burst.trim())?,
+        per_sec: parse_value(key, per_sec.trim())?,
+    };
+
+    if !rate.per_sec.is_finite() || rate.per_sec < 0.0 {
+        return Err(format!(
+            "invalid refill rate '{}' for {}",
+            per_sec.trim(),
+            key
+        ));
+    }
+    Ok(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_inside_a_value() {
+        let config = Config::parse(
+            "operator_password = pw#secret\nadmin_token = t#1\nmotd = room #1 rules\n",
+        )
+        .unwrap();
+        assert_eq!(config.operator_password, "pw#secret");
+        assert_eq!(config.admin_token, "t#1");
+        assert_eq!(config.motd, "room #1 rules");
+    }
+
+    #[test]
+    fn comment_lines() {
+        let config = Config::parse("# a comment\n   # indented = comment\n\nmotd = hi\n").unwrap();
+        assert_eq!(config.motd, "hi");
+    }
+
+    #[test]
+    fn line_without_a_value() {
+        let result = Config::parse("motd = hi\nnonsense\n");
+        assert_eq!(result.err().unwrap(), "line 2: expected 'key = value'");
+    }
+}
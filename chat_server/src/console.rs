@@ -0,0 +1,225 @@
+// Operator console on the server's stdin, working on the same state as the client threads
+
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+use crate::audit::AuditEntry;
+use crate::health::Health;
+use crate::logging::log_info;
+use crate::{
+    address_of, announce, ban_user, begin_handoff, begin_shutdown, reload_config, review_reports,
+    Server,
+};
+
+const HELP: &str = "\
+Commands:
+  who                      list connected users
+  rooms                    show the room and its waiting queue
+  stats                    show server statistics
+  health                   show the health check line
+  kick <nickname> [reason] disconnect a user
+  ban <nickname> [reason]  ban a user
+  broadcast <message>      send a notice to everyone
+  reports [show <id>]      list open reports or show one with its messages
+  reports resolve <id> <dismiss|warn|mute|ban>
+                           close a report and act on the reported user
+  reload                   re-read the config file
+  restart                  hand the sessions to a freshly started server
+  shutdown [secs] [reason] warn everyone, then disconnect them and stop
+";
+
+// read commands until stdin closes, a server without a terminal just never gets any
+pub fn run(server: Arc<Server>) {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        print!("{}", execute(&server, "console", line));
+        let _ = io::stdout().flush();
+    }
+}
+
+// run one command on behalf of `actor`, returns the reply lines
+pub fn execute(server: &Arc<Server>, actor: &str, line: &str) -> String {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+
+    match command {
+        "help" => HELP.to_string(),
+        "who" => who(server),
+        "rooms" => rooms(server),
+        "stats" => stats(server),
+        "health" => Health::check(server).line(),
+        "kick" | "ban" => {
+            let (target, reason) = args.split_once(' ').unwrap_or((args, ""));
+            let reason = match reason.trim() {
+                "" => "no reason given",
+                reason => reason,
+            };
+            if target.is_empty() {
+                format!("Usage: {} <nickname> [reason]\n", command)
+            } else if command == "kick" {
+                kick(server, actor, target, reason)
+            } else {
+                ban(server, actor, target, reason)
+            }
+        }
+        "broadcast" => {
+            if args.is_empty() {
+                return "Usage: broadcast <message>\n".to_string();
+            }
+            let clients_lock = server.clients.read().unwrap();
+            announce(
+                server,
+                &clients_lock,
+                &format!("[Server notice: {}]\n", args),
+                None,
+            );
+            format!("Sent to {} users\n", clients_lock.len())
+        }
+        "reports" => review_reports(server, actor, command, args),
+        "reload" => match reload_config(server) {
+            Ok(reply) => format!("{}\n", reply),
+            Err(e) => format!("Error: {}\n", e),
+        },
+        "restart" => {
+            if begin_handoff(server, actor) {
+                "Handing off to a new process\n".to_string()
+            } else {
+                "Error: A shutdown or handoff is already running\n".to_string()
+            }
+        }
+        "shutdown" => {
+            let config = server.config();
+            // an optional countdown in seconds, then the reason
+            let (secs, rest) = args.split_once(' ').unwrap_or((args, ""));
+            let (countdown, reason) = match secs.parse() {
+                Ok(countdown) => (countdown, rest.trim()),
+                Err(_) => (config.shutdown_countdown.as_secs(), args),
+            };
+            let reason = if reason.is_empty() {
+                config.shutdown_message.as_str()
+            } else {
+                reason
+            };
+            if begin_shutdown(server, actor, countdown, reason) {
+                format!("Shutting down in {}s\n", countdown)
+            } else {
+                "Error: A shutdown is already running\n".to_string()
+            }
+        }
+        _ => format!("Unknown command '{}', type 'help' for commands\n", command),
+    }
+}
+
+fn who(server: &Server) -> String {
+    let clients_lock = server.clients.read().unwrap();
+    let moderation = server.moderation.lock().unwrap();
+
+    let mut nicknames: Vec<&String> = clients_lock.keys().collect();
+    nicknames.sort();
+
+    let mut reply = format!("{} users connected\n", nicknames.len());
+    for nickname in nicknames {
+        let client = &clients_lock[nickname];
+        reply.push_str(&format!("  {} {}:{}", nickname, client.ip, client.port));
+        if client.operator {
+            reply.push_str(" [operator]");
+        }
+        if moderation.is_shadow_banned(nickname) {
+            reply.push_str(" [shadow-banned]");
+        }
+        if let Some(remaining) = moderation.muted_for(nickname) {
+            reply.push_str(&format!(" [muted {}s]", remaining.as_secs() + 1));
+        }
+        reply.push('\n');
+    }
+    reply
+}
+
+// the server has a single room, shown with its capacity and waiting queue
+fn rooms(server: &Server) -> String {
+    let config = server.config();
+    let users = server.clients.read().unwrap().len();
+    format!(
+        "main: {}/{} users, {}/{} waiting\n",
+        users,
+        config.max_clients,
+        server.waiting.len(),
+        config.waiting_queue_size
+    )
+}
+
+fn stats(server: &Server) -> String {
+    let (users, operators) = {
+        let clients_lock = server.clients.read().unwrap();
+        let operators = clients_lock.values().filter(|c| c.operator).count();
+        (clients_lock.len(), operators)
+    };
+    let shadow_banned = server.moderation.lock().unwrap().shadow_banned().len();
+    let open_reports = server.reports.lock().unwrap().open_reports().count();
+    let uptime = server.started.elapsed().as_secs();
+
+    format!(
+        "uptime {}h {}m {}s\nusers {} ({} operators), {} waiting\nshadow-banned {}\nopen reports {}\nfilter policy version {:016x}\n",
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60,
+        users,
+        operators,
+        server.waiting.len(),
+        shadow_banned,
+        open_reports,
+        server.config().filter.version()
+    )
+}
+
+fn kick(server: &Server, actor: &str, target: &str, reason: &str) -> String {
+    let mut clients_lock = server.clients.write().unwrap();
+    let target_addr = address_of(&clients_lock, target);
+    let client = match clients_lock.remove(target) {
+        Some(client) => client,
+        None => return format!("{} is not connected\n", target),
+    };
+    client.disconnect(&format!("You were kicked by the server: {}\n", reason));
+    server.votes.lock().unwrap().cancel_for(target);
+
+    server.audit.record(AuditEntry {
+        action: "kick",
+        actor,
+        actor_addr: None,
+        target: Some(target),
+        target_addr: target_addr.as_deref(),
+        reason,
+    });
+
+    let msg = format!(
+        "[{} was removed by the server. {} users remain.]\n",
+        target,
+        clients_lock.len()
+    );
+    announce(server, &clients_lock, &msg, None);
+    log_info!(
+        "{} was kicked by {}. There are {} users now",
+        target,
+        actor,
+        clients_lock.len()
+    );
+    format!("Kicked {}\n", target)
+}
+
+fn ban(server: &Server, actor: &str, target: &str, reason: &str) -> String {
+    let mut clients_lock = server.clients.write().unwrap();
+    if !clients_lock.contains_key(target) {
+        return format!("{} is not connected\n", target);
+    }
+    ban_user(server, &mut clients_lock, actor, target, reason);
+    format!("Banned {}\n", target)
+}
@@ -0,0 +1,55 @@
+// Running in the background without systemd: detaching and the PID file
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::process;
+
+// Fork into the background and leave the terminal, the parent exits. Must run
+// before any thread is started. The working directory stays, relative paths in
+// the config keep working.
+pub fn daemonize() -> io::Result<()> {
+    // SAFETY: the process is still single-threaded, and the parents exit at once
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // the session leader exits too, so the daemon can never get a terminal again
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+    }
+
+    // nothing reads or writes the terminal from here on, the log needs log_file
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        // SAFETY: dup2 onto the standard descriptors, which this process owns
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn write_pid_file(path: &str) -> io::Result<()> {
+    fs::write(path, format!("{}\n", process::id()))
+}
+
+// remove the PID file, unless a process this one handed off to has taken it over
+pub fn remove_pid_file(path: &str) {
+    let ours = fs::read_to_string(path)
+        .is_ok_and(|pid| pid.trim().parse::<u32>().ok() == Some(process::id()));
+    if ours {
+        let _ = fs::remove_file(path);
+    }
+}
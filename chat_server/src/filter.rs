@@ -0,0 +1,48 @@
+// Prohibited-content rules, advertised to clients so they can warn before sending
+
+pub struct FilterPolicy {
+    // lowercase phrases, a message containing any of them is prohibited
+    phrases: Vec<String>,
+    version: u64,
+}
+
+impl FilterPolicy {
+    pub fn new(phrases: Vec<String>) -> Self {
+        let phrases: Vec<String> = phrases.iter().map(|p| p.to_lowercase()).collect();
+        let version = fnv1a(phrases.join("\n").as_bytes());
+
+        FilterPolicy { phrases, version }
+    }
+
+    // check for prohibited message content
+    pub fn matches(&self, content: &str) -> bool {
+        let content = content.to_lowercase();
+        self.phrases.iter().any(|phrase| content.contains(phrase))
+    }
+
+    // hash of the rules, changes whenever the rules change
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // payload of the policy frame: "<version> <phrase>\t<phrase>..."
+    pub fn encode(&self) -> String {
+        format!("{:016x} {}", self.version, self.phrases.join("\t"))
+    }
+}
+
+impl Default for FilterPolicy {
+    fn default() -> Self {
+        FilterPolicy::new(vec!["i hate professor".to_string()])
+    }
+}
+
+// 64-bit FNV-1a, stable across builds unlike DefaultHasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
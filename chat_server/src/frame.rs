@@ -0,0 +1,169 @@
+// Reading newline-terminated frames without letting a peer grow the buffer
+
+use std::io::{self, BufRead};
+
+// What read_frame found
+pub enum Frame {
+    // a complete frame is in the buffer, without its newline
+    Line,
+    // the frame was longer than allowed and was skipped up to its newline
+    TooLong,
+    Eof,
+}
+
+// Read one frame into `buffer`, keeping at most `max_len` bytes of it in memory
+pub fn read_frame<R: BufRead>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<Frame> {
+    buffer.clear();
+    continue_frame(reader, buffer, max_len)
+}
+
+// Like read_frame, but goes on with the start of a frame an interrupted read left in
+// `buffer`, e.g. after a read timeout
+pub fn continue_frame<R: BufRead>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<Frame> {
+    // a frame found too long is marked by keeping one byte over the limit
+    let mut too_long = buffer.len() > max_len;
+
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        // end of stream, an unterminated partial frame is dropped
+        if available.is_empty() {
+            return Ok(Frame::Eof);
+        }
+
+        let (chunk_len, complete) = match available.iter().position(|b| *b == b'\n') {
+            Some(idx) => (idx, true),
+            None => (available.len(), false),
+        };
+
+        if !too_long {
+            if buffer.len() + chunk_len > max_len {
+                too_long = true;
+                buffer.resize(max_len + 1, 0);
+            } else {
+                buffer.extend_from_slice(&available[..chunk_len]);
+            }
+        }
+
+        if complete {
+            reader.consume(chunk_len + 1);
+            return Ok(if too_long {
+                buffer.clear();
+                Frame::TooLong
+            } else {
+                Frame::Line
+            });
+        }
+        reader.consume(chunk_len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::{BufReader, Read};
+
+    // hands out its chunks one read at a time, None is a read that times out
+    struct Chunks(VecDeque<Option<&'static [u8]>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Some(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Ok(chunk.len())
+                }
+                Some(None) => Err(io::ErrorKind::WouldBlock.into()),
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn reader(chunks: &[Option<&'static [u8]>]) -> BufReader<Chunks> {
+        BufReader::new(Chunks(chunks.iter().copied().collect()))
+    }
+
+    #[test]
+    fn exactly_max_len() {
+        let mut reader = reader(&[Some(b"abcd\n")]);
+        let mut buffer = Vec::new();
+        assert!(matches!(
+            read_frame(&mut reader, &mut buffer, 4),
+            Ok(Frame::Line)
+        ));
+        assert_eq!(buffer, b"abcd");
+    }
+
+    #[test]
+    fn one_byte_over_is_skipped() {
+        let mut reader = reader(&[Some(b"abcde\nnext\n")]);
+        let mut buffer = Vec::new();
+        assert!(matches!(
+            read_frame(&mut reader, &mut buffer, 4),
+            Ok(Frame::TooLong)
+        ));
+        assert!(buffer.is_empty());
+        assert!(matches!(
+            read_frame(&mut reader, &mut buffer, 4),
+            Ok(Frame::Line)
+        ));
+        assert_eq!(buffer, b"next");
+    }
+
+    #[test]
+    fn split_read_continues() {
+        let mut reader = reader(&[Some(b"he"), None, Some(b"llo\n")]);
+        let mut buffer = Vec::new();
+        let timed_out = read_frame(&mut reader, &mut buffer, 8);
+        assert_eq!(timed_out.err().unwrap().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(buffer, b"he");
+        assert!(matches!(
+            continue_frame(&mut reader, &mut buffer, 8),
+            Ok(Frame::Line)
+        ));
+        assert_eq!(buffer, b"hello");
+    }
+
+    #[test]
+    fn split_read_over_the_limit() {
+        let mut reader = reader(&[Some(b"abc"), None, Some(b"de"), None, Some(b"f\nok\n")]);
+        let mut buffer = Vec::new();
+        assert!(read_frame(&mut reader, &mut buffer, 4).is_err());
+        assert_eq!(buffer, b"abc");
+        // the marker byte over the limit is all that is kept of the rest
+        assert!(continue_frame(&mut reader, &mut buffer, 4).is_err());
+        assert_eq!(buffer.len(), 5);
+        assert!(matches!(
+            continue_frame(&mut reader, &mut buffer, 4),
+            Ok(Frame::TooLong)
+        ));
+        assert!(matches!(
+            read_frame(&mut reader, &mut buffer, 4),
+            Ok(Frame::Line)
+        ));
+        assert_eq!(buffer, b"ok");
+    }
+
+    #[test]
+    fn unterminated_frame_at_eof() {
+        let mut reader = reader(&[Some(b"partial")]);
+        let mut buffer = Vec::new();
+        assert!(matches!(
+            read_frame(&mut reader, &mut buffer, 16),
+            Ok(Frame::Eof)
+        ));
+    }
+}
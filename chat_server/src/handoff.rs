@@ -0,0 +1,486 @@
+// Zero-downtime restart: pass the listening socket and the sessions to a new process
+//
+// The old process stops reading from its clients, writes the session state to a
+// file in a new private directory and starts its own binary again with HANDOFF_ENV
+// naming that file. The sockets are inherited as open descriptors:
+//
+//   listener 3 0.0.0.0:20417           fd, configured address
+//   listener 4 unix:/run/chat.sock
+//   next_id 42
+//   session 7 12 1 0 68656c6c 203.0.113.7:50312 alice
+//                                      fd, id, operator, whether the rest of a too
+//                                      long frame is being skipped, unread input in
+//                                      hex, address (it may have come from a PROXY
+//                                      header), nickname
+//   mute 30000 bob                     milliseconds left
+//   shadowban carol
+//   next_report 7
+//   report 6 1760000000 open alice bob 7370616d
+//                                      id, filed at, "open" or resolution:operator,
+//                                      reporter, target, reason in hex
+//   evidence 6 5b31323a30305d...       a captured message of report 6, in hex
+//
+// The new process takes the sockets over and removes the file, which tells the
+// old one to exit. Strikes, votes and the message history start over, clients in
+// the waiting queue are asked to reconnect.
+
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::net::{Listener, Stream};
+use crate::reports::{Report, Resolution};
+
+pub const HANDOFF_ENV: &str = "CHAT_SERVER_HANDOFF";
+
+// how long the new process gets to take over before the old one gives up on it
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Session {
+    pub stream: Stream,
+    pub id: u64,
+    pub nickname: String,
+    pub operator: bool,
+    pub pending: Unread,
+}
+
+// What a client thread had read but not handled when it stopped
+#[derive(Clone, Default)]
+pub struct Unread {
+    // goes first in the new process
+    pub input: Vec<u8>,
+    // the thread was in a frame over the size limit, input up to its newline is
+    // dropped
+    pub skipping: bool,
+}
+
+pub struct State {
+    pub listeners: Vec<(String, Listener)>,
+    // the listeners came from systemd, the config's listen addresses are not ours to bind
+    pub socket_activated: bool,
+    pub next_id: u64,
+    pub sessions: Vec<Session>,
+    pub muted: Vec<(String, Duration)>,
+    pub shadow_banned: Vec<String>,
+    pub reports: Vec<Report>,
+    pub next_report_id: u64,
+}
+
+// Start the new process and wait until it has taken over, returns its pid
+pub fn start_successor(state: &State) -> io::Result<u32> {
+    // the state holds addresses and unread input, only this user may see it
+    let dir = private_dir()?;
+    let path = dir.join("state");
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)
+        .and_then(|mut file| file.write_all(serialize(state).as_bytes()));
+    if let Err(e) = written {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(e);
+    }
+
+    let mut fds: Vec<RawFd> = state.listeners.iter().map(|(_, l)| l.as_raw_fd()).collect();
+    fds.extend(state.sessions.iter().map(|s| s.stream.as_raw_fd()));
+
+    let result = fds
+        .iter()
+        .try_for_each(|fd| set_inherited(*fd, true))
+        .and_then(|()| {
+            Command::new(env::current_exe()?)
+                .args(env::args_os().skip(1))
+                .env(HANDOFF_ENV, &path)
+                // the watchdog is the new process's job once it is the main one
+                .env_remove("WATCHDOG_PID")
+                .spawn()
+        })
+        .and_then(|child| wait_ready(child, &path));
+
+    // only the one child may inherit them
+    for fd in fds {
+        let _ = set_inherited(fd, false);
+    }
+    // the new process has removed the file already when it took over
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+// a new directory only this user can enter, mkdtemp never reuses an existing one
+fn private_dir() -> io::Result<PathBuf> {
+    let template = env::temp_dir().join("chat_server-handoff-XXXXXX");
+    let mut template = CString::new(template.into_os_string().into_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .into_bytes_with_nul();
+    // SAFETY: the template is NUL-terminated, mkdtemp replaces the Xs in place
+    if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+// The state left by the previous process if this one was started as its successor.
+// Remove the returned file once the sessions are running again.
+pub fn take_over() -> io::Result<Option<(State, PathBuf)>> {
+    let path = match env::var_os(HANDOFF_ENV) {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(None),
+    };
+    let text = fs::read_to_string(&path)?;
+    let state = deserialize(&text).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })?;
+    Ok(Some((state, path)))
+}
+
+fn wait_ready(mut child: Child, path: &Path) -> io::Result<u32> {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        if !path.exists() {
+            return Ok(child.id());
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!(
+                "new process exited with {}",
+                status
+            )));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "new process did not take over in time",
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// clear or set close-on-exec, sockets from std have it set
+pub fn set_inherited(fd: RawFd, inherited: bool) -> io::Result<()> {
+    // SAFETY: fcntl on a descriptor we own, with no pointers involved
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if inherited {
+            flags & !libc::FD_CLOEXEC
+        } else {
+            flags | libc::FD_CLOEXEC
+        };
+        if libc::fcntl(fd, libc::F_SETFD, flags) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn serialize(state: &State) -> String {
+    let mut text = String::new();
+    for (address, listener) in &state.listeners {
+        text.push_str(&format!("listener {} {}\n", listener.as_raw_fd(), address));
+    }
+    text.push_str(&format!(
+        "socket_activated {}\n",
+        state.socket_activated as u8
+    ));
+    text.push_str(&format!("next_id {}\n", state.next_id));
+    for session in &state.sessions {
+        let input = encode_hex(&session.pending.input);
+        text.push_str(&format!(
+            "session {} {} {} {} {} {} {}\n",
+            session.stream.as_raw_fd(),
+            session.id,
+            session.operator as u8,
+            session.pending.skipping as u8,
+            input,
+            session.stream.peer_addr(),
+            session.nickname
+        ));
+    }
+    for (nickname, remaining) in &state.muted {
+        text.push_str(&format!("mute {} {}\n", remaining.as_millis(), nickname));
+    }
+    for nickname in &state.shadow_banned {
+        text.push_str(&format!("shadowban {}\n", nickname));
+    }
+    text.push_str(&format!("next_report {}\n", state.next_report_id));
+    for report in &state.reports {
+        let status = match &report.resolved {
+            Some((resolution, by)) => format!("{}:{}", resolution.name(), by),
+            None => "open".to_string(),
+        };
+        text.push_str(&format!(
+            "report {} {} {} {} {} {}\n",
+            report.id,
+            report.at,
+            status,
+            report.reporter,
+            report.target,
+            encode_hex(report.reason.as_bytes())
+        ));
+        for message in &report.messages {
+            text.push_str(&format!(
+                "evidence {} {}\n",
+                report.id,
+                encode_hex(message.as_bytes())
+            ));
+        }
+    }
+    text
+}
+
+fn deserialize(text: &str) -> Result<State, String> {
+    let mut listeners = Vec::new();
+    let mut socket_activated = false;
+    let mut next_id = 0;
+    let mut sessions = Vec::new();
+    let mut muted = Vec::new();
+    let mut shadow_banned = Vec::new();
+    let mut reports: Vec<Report> = Vec::new();
+    let mut next_report_id = 0;
+
+    for (idx, line) in text.lines().enumerate() {
+        let invalid = || format!("line {}: invalid entry '{}'", idx + 1, line);
+        let (kind, rest) = line.split_once(' ').ok_or_else(invalid)?;
+        match kind {
+            "listener" => {
+                let (fd, address) = rest.split_once(' ').ok_or_else(invalid)?;
+                let fd = fd.parse().map_err(|_| invalid())?;
+                // SAFETY: the previous process left this descriptor open for us, once
+                let listener = unsafe { Listener::from_raw_fd(fd) }.map_err(|e| e.to_string())?;
+                listeners.push((address.to_string(), listener));
+            }
+            "socket_activated" => socket_activated = rest == "1",
+            "next_id" => next_id = rest.parse().map_err(|_| invalid())?,
+            "session" => {
+                let fields: Vec<&str> = rest.splitn(7, ' ').collect();
+                if fields.len() != 7 {
+                    return Err(invalid());
+                }
+                let fd: RawFd = fields[0].parse().map_err(|_| invalid())?;
+                let id = fields[1].parse().map_err(|_| invalid())?;
+                let input = match fields[4] {
+                    "-" => Vec::new(),
+                    hex => decode_hex(hex).ok_or_else(invalid)?,
+                };
+                let peer = fields[5].parse().map_err(|_| invalid())?;
+                // SAFETY: as for the listener
+                let mut stream = match unsafe { Stream::from_raw_fd(fd) } {
+                    Ok(stream) => stream,
+                    // the user left while the sessions were handed over
+                    Err(_) => continue,
+                };
+                stream.set_peer_addr(peer);
+                sessions.push(Session {
+                    stream,
+                    id,
+                    nickname: fields[6].to_string(),
+                    operator: fields[2] == "1",
+                    pending: Unread {
+                        input,
+                        skipping: fields[3] == "1",
+                    },
+                });
+            }
+            "mute" => {
+                let (millis, nickname) = rest.split_once(' ').ok_or_else(invalid)?;
+                let millis = millis.parse().map_err(|_| invalid())?;
+                muted.push((nickname.to_string(), Duration::from_millis(millis)));
+            }
+            "shadowban" => shadow_banned.push(rest.to_string()),
+            "next_report" => next_report_id = rest.parse().map_err(|_| invalid())?,
+            "report" => {
+                let fields: Vec<&str> = rest.split(' ').collect();
+                if fields.len() != 6 {
+                    return Err(invalid());
+                }
+                let resolved = match fields[2] {
+                    "open" => None,
+                    status => {
+                        let (resolution, by) = status.split_once(':').ok_or_else(invalid)?;
+                        let resolution = Resolution::parse(resolution).ok_or_else(invalid)?;
+                        Some((resolution, by.to_string()))
+                    }
+                };
+                reports.push(Report {
+                    id: fields[0].parse().map_err(|_| invalid())?,
+                    at: fields[1].parse().map_err(|_| invalid())?,
+                    reporter: fields[3].to_string(),
+                    target: fields[4].to_string(),
+                    reason: decode_text(fields[5]).ok_or_else(invalid)?,
+                    messages: Vec::new(),
+                    resolved,
+                });
+            }
+            "evidence" => {
+                let (id, hex) = rest.split_once(' ').ok_or_else(invalid)?;
+                let id: u64 = id.parse().map_err(|_| invalid())?;
+                let report = reports
+                    .iter_mut()
+                    .find(|report| report.id == id)
+                    .ok_or_else(invalid)?;
+                report.messages.push(decode_text(hex).ok_or_else(invalid)?);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(State {
+        listeners,
+        socket_activated,
+        next_id,
+        sessions,
+        muted,
+        shadow_banned,
+        reports,
+        next_report_id,
+    })
+}
+
+// "-" stands for nothing, so no field is ever empty
+fn encode_hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_string();
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_text(hex: &str) -> Option<String> {
+    match hex {
+        "-" => Some(String::new()),
+        hex => String::from_utf8(decode_hex(hex)?).ok(),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    // a descriptor number nothing has open
+    const CLOSED_FD: RawFd = 1_000_000;
+
+    fn session_fd() -> RawFd {
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        ours.into_raw_fd()
+    }
+
+    #[test]
+    fn decode_hex_pairs() {
+        assert_eq!(decode_hex("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn decode_hex_odd_length() {
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("0"), None);
+    }
+
+    #[test]
+    fn decode_hex_not_hex() {
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("éé"), None);
+    }
+
+    #[test]
+    fn session_with_pending_input() {
+        let text = format!(
+            "session {} 7 1 0 0768690a 203.0.113.7:50312 alice\n",
+            session_fd()
+        );
+        let state = deserialize(&text).unwrap();
+        let session = &state.sessions[0];
+        assert_eq!(session.id, 7);
+        assert_eq!(session.nickname, "alice");
+        assert!(session.operator);
+        assert_eq!(session.pending.input, b"\x07hi\n");
+        assert!(!session.pending.skipping);
+        assert_eq!(session.stream.peer_addr().to_string(), "203.0.113.7:50312");
+    }
+
+    #[test]
+    fn session_whose_fd_is_missing_is_skipped() {
+        let text = format!(
+            "session {} 7 0 0 - 203.0.113.7:50312 alice\nsession {} 8 0 1 - 203.0.113.8:50313 bob\n",
+            CLOSED_FD,
+            session_fd()
+        );
+        let state = deserialize(&text).unwrap();
+        assert_eq!(state.sessions.len(), 1);
+        assert_eq!(state.sessions[0].nickname, "bob");
+        assert!(state.sessions[0].pending.skipping);
+    }
+
+    #[test]
+    fn session_with_odd_length_input() {
+        let text = format!("session {} 7 0 0 abc 203.0.113.7:50312 alice\n", CLOSED_FD);
+        assert!(deserialize(&text).is_err());
+    }
+
+    #[test]
+    fn unknown_entry() {
+        assert!(deserialize("listener\n").is_err());
+        assert!(deserialize("frobnicate 1\n").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let state = State {
+            listeners: Vec::new(),
+            socket_activated: true,
+            next_id: 42,
+            sessions: Vec::new(),
+            muted: vec![("carol".to_string(), Duration::from_millis(1500))],
+            shadow_banned: vec!["mallory".to_string()],
+            reports: vec![Report {
+                id: 3,
+                at: 1_700_000_000,
+                reporter: "alice".to_string(),
+                target: "bob".to_string(),
+                reason: "spam, twice".to_string(),
+                messages: vec!["[2023-11-14T22:13:20Z] bob> buy now".to_string()],
+                resolved: Some((Resolution::Warn, "carol".to_string())),
+            }],
+            next_report_id: 3,
+        };
+
+        let restored = deserialize(&serialize(&state)).unwrap();
+        assert!(restored.socket_activated);
+        assert_eq!(restored.next_id, 42);
+        assert_eq!(restored.muted, state.muted);
+        assert_eq!(restored.shadow_banned, state.shadow_banned);
+        assert_eq!(restored.next_report_id, 3);
+        let report = &restored.reports[0];
+        assert_eq!(report.summary(), state.reports[0].summary());
+        assert_eq!(report.messages, state.reports[0].messages);
+    }
+}
@@ -0,0 +1,89 @@
+// Health of the server for load balancers and orchestration, one logfmt line:
+//
+//   status=ok ready=true uptime=3600 users=3 max_users=4 waiting=0 storage=ok
+//
+// It is served as a handshake frame that never joins the room and as GET /healthz
+// on the metrics listener. The server is not ready while it shuts down or hands
+// off, and storage names the first file whose last write failed. A probe only
+// reads what the writers recorded, it never touches the disk itself.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crate::audit::json_string;
+use crate::{logging, Server};
+
+pub struct Health {
+    ready: bool,
+    uptime_secs: u64,
+    users: usize,
+    max_users: usize,
+    waiting: usize,
+    storage: Result<(), String>,
+}
+
+impl Health {
+    pub fn check(server: &Server) -> Health {
+        let ready = !server.shutting_down.load(Ordering::SeqCst)
+            && !server.handing_off.load(Ordering::SeqCst);
+
+        let storage = [
+            ("audit log", server.audit.last_error()),
+            ("transcript", server.transcript.last_error()),
+            ("log file", logging::last_error()),
+        ]
+        .into_iter()
+        .try_for_each(|(name, error)| match error {
+            Some(e) => Err(format!("{}: {}", name, e)),
+            None => Ok(()),
+        });
+
+        Health {
+            ready,
+            uptime_secs: server.started.elapsed().as_secs(),
+            users: server.clients.read().unwrap().len(),
+            max_users: server.config().max_clients,
+            waiting: server.waiting.len(),
+            storage,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.ready && self.storage.is_ok()
+    }
+
+    pub fn line(&self) -> String {
+        let storage = match &self.storage {
+            Ok(()) => "ok".to_string(),
+            Err(e) => json_string(e),
+        };
+        format!(
+            "status={} ready={} uptime={} users={} max_users={} waiting={} storage={}\n",
+            if self.is_ok() { "ok" } else { "failing" },
+            self.ready,
+            self.uptime_secs,
+            self.users,
+            self.max_users,
+            self.waiting,
+            storage
+        )
+    }
+}
+
+// The error of the last write or sync of a file, cleared by the next one that works
+pub struct LastError(Mutex<Option<String>>);
+
+impl LastError {
+    pub const fn new() -> LastError {
+        LastError(Mutex::new(None))
+    }
+
+    pub fn record<T>(&self, result: &io::Result<T>) {
+        *self.0.lock().unwrap() = result.as_ref().err().map(|e| e.to_string());
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,135 @@
+// Leveled server log, one logfmt line per event, to stderr or a rotating file
+//
+//   ts=2026-01-01T12:00:00Z level=info conn=7 msg="alice joined from 10.0.0.2:50312"
+//
+// Lines about a connection carry its id so one session can be followed with grep.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use crate::audit::json_string;
+use crate::config::{Config, LogLevel};
+use crate::health::LastError;
+use crate::rotate::RotatingFile;
+use crate::timestamp;
+
+struct Logger {
+    level: AtomicU8,
+    // message text is only logged when this is set
+    log_content: AtomicBool,
+    // stderr when None
+    file: Mutex<Option<RotatingFile>>,
+    // kept apart from the file so a health probe never waits on a write
+    last_error: LastError,
+}
+
+static LOGGER: Logger = Logger {
+    level: AtomicU8::new(LogLevel::Info as u8),
+    log_content: AtomicBool::new(false),
+    file: Mutex::new(None),
+    last_error: LastError::new(),
+};
+
+// open the log file if one is configured and apply the level settings
+pub fn init(config: &Config) -> io::Result<()> {
+    if !config.log_file.is_empty() {
+        let file = RotatingFile::open(&config.log_file, config.log_max_bytes, config.log_keep)?;
+        *LOGGER.file.lock().unwrap() = Some(file);
+    }
+    configure(config);
+    Ok(())
+}
+
+// settings that can change on a config reload, the log file stays until a restart
+pub fn configure(config: &Config) {
+    LOGGER
+        .level
+        .store(config.log_level as u8, Ordering::Relaxed);
+    LOGGER
+        .log_content
+        .store(config.log_content, Ordering::Relaxed);
+}
+
+pub fn sync() -> io::Result<()> {
+    let result = match LOGGER.file.lock().unwrap().as_ref() {
+        Some(file) => file.sync(),
+        None => Ok(()),
+    };
+    LOGGER.last_error.record(&result);
+    result
+}
+
+pub fn last_error() -> Option<String> {
+    LOGGER.last_error.get()
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LOGGER.level.load(Ordering::Relaxed)
+}
+
+// user-written text as it may appear in the log
+pub fn content(text: &str) -> String {
+    if LOGGER.log_content.load(Ordering::Relaxed) {
+        json_string(text)
+    } else {
+        format!("<redacted, {} bytes>", text.len())
+    }
+}
+
+// use the log_* macros instead of calling this directly
+pub fn write(level: LogLevel, conn: Option<u64>, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let conn = conn.map_or_else(String::new, |conn| format!(" conn={}", conn));
+    let line = format!(
+        "ts={} level={}{} msg={}\n",
+        timestamp::format_utc(timestamp::unix_now()),
+        level.name(),
+        conn,
+        json_string(&args.to_string())
+    );
+
+    let mut file = LOGGER.file.lock().unwrap();
+    match file.as_mut() {
+        Some(file) => {
+            let result = file.write(&line);
+            LOGGER.last_error.record(&result);
+            if let Err(e) = result {
+                eprint!("{}", line);
+                eprintln!("Error writing log file {}: {}", file.path(), e);
+            }
+        }
+        None => eprint!("{}", line),
+    }
+}
+
+macro_rules! log_at {
+    ($level:expr, conn = $conn:expr; $($arg:tt)+) => {
+        $crate::logging::write($level, Some($conn), format_args!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::write($level, None, format_args!($($arg)+))
+    };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::config::LogLevel::Error, $($arg)+) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::config::LogLevel::Warn, $($arg)+) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::config::LogLevel::Info, $($arg)+) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::config::LogLevel::Debug, $($arg)+) };
+}
+
+pub(crate) use {log_at, log_debug, log_error, log_info, log_warn};
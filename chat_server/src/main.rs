@@ -2,12 +2,63 @@
 // Student ID: 20220417
 // “Network Applications and Design” Homework Assignment #4
 
+mod admin;
+mod admission;
+mod audit;
+mod challenge;
+mod config;
+mod console;
+mod daemon;
+mod filter;
+mod frame;
+mod handoff;
+mod health;
+mod logging;
+mod metrics;
+mod moderation;
+mod net;
+mod proxy;
+mod ratelimit;
+mod reports;
+mod rotate;
+mod sanitize;
+mod spam;
+mod systemd;
+mod timestamp;
+mod transcript;
+mod votekick;
+
 use std::collections::HashMap;
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::env;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+use signal_hook::iterator::Signals;
+
+use admission::{Handshakes, WaitingQueue};
+use audit::{AuditEntry, AuditLog};
+use challenge::Challenge;
+use config::{Config, LogLevel, SpamAction};
+use frame::Frame;
+use handoff::{Session, Unread};
+use health::Health;
+use logging::{log_debug, log_error, log_info, log_warn};
+use metrics::METRICS;
+use moderation::{Moderation, Offense, StrikeAction};
+use net::{Listener, PeerAddr, Stream};
+use ratelimit::{RateClass, RateDecision, RateLimiter};
+use reports::{MessageBuffer, ReportQueue, Resolution};
+use spam::SpamDetector;
+use transcript::Transcript;
+use votekick::{VoteKick, VoteOutcome};
 
 // Command codes - 1 byte encoding for commands
 const CMD_LIST: u8 = 1;
@@ -17,51 +68,241 @@ const CMD_BAN: u8 = 4;
 const CMD_PING: u8 = 5;
 const CMD_EXIT: u8 = 6;
 const CMD_CHAT: u8 = 7;
+const CMD_OPER: u8 = 8;
+const CMD_VOTEKICK: u8 = 9;
+// 10 is b'\n', the frame terminator, so it is never a command
+const CMD_AUDIT: u8 = 11;
+const CMD_REPORT: u8 = 12;
+const CMD_REPORTS: u8 = 13;
+const CMD_SHADOWBAN: u8 = 14;
+// sent instead of the nickname, answered with one health line without joining
+const CMD_HEALTH: u8 = 15;
+
+// Event codes - 1 byte prefix for server frames that are not plain text
+const EVT_POLICY: u8 = 16;
+// text written by a user, any line without it comes from the server itself
+const EVT_MESSAGE: u8 = 17;
+// the client is in the waiting queue, the payload tells its position
+const EVT_QUEUE: u8 = 18;
+// a join challenge the client must answer with a plain line
+const EVT_CHALLENGE: u8 = 19;
 
-// Maximum number of clients allowed
+// Maximum number of clients allowed, unless the config says otherwise
 const MAX_CLIENTS: usize = 4;
 
-// Port to listen on - replace with your designated port number
+// How long a shutdown waits for clients to close after the final notice
+const SHUTDOWN_LINGER: Duration = Duration::from_secs(1);
+
+// How often client threads look up from an idle socket, to notice a handoff
+const READ_POLL: Duration = Duration::from_millis(250);
+
+// How long a handoff waits for every client thread to stop reading
+const HANDOFF_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a new process keeps trying ports the old one has not let go of yet
+const HANDOFF_BIND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Port to listen on when the config names no listen address - replace with your
+// designated port number
 const PORT: u16 = 20417;
 
 // Structure to store client information
 struct Client {
+    // unique per connection, tells a reconnect apart from the old session
+    id: u64,
     nickname: String,
-    stream: TcpStream,
+    stream: Stream,
     ip: String,
     port: u16,
+    operator: bool,
+    // held for each message, threads relaying under the shared registry lock
+    // would otherwise interleave their bytes on the socket
+    sending: Mutex<()>,
 }
 
 impl Client {
-    fn new(nickname: String, stream: TcpStream) -> Self {
-        let peer_addr = stream.peer_addr().unwrap();
+    fn new(id: u64, nickname: String, stream: Stream) -> Self {
+        let peer_addr = stream.peer_addr();
         let ip = peer_addr.ip().to_string();
         let port = peer_addr.port();
 
         Client {
+            id,
             nickname,
             stream,
             ip,
             port,
+            operator: false,
+            sending: Mutex::new(()),
         }
     }
 
     // send a message to the client
     fn send_message(&self, message: &str) -> io::Result<()> {
-        let mut stream = self.stream.try_clone()?;
-        stream.write_all(message.as_bytes())?;
-        stream.flush()?;
-        Ok(())
+        let _sending = self.sending.lock().unwrap();
+        send_to(&self.stream, message)
+    }
+
+    // send a last message and close the connection, its thread then exits on its own
+    fn disconnect(&self, message: &str) {
+        let _ = self.send_message(message);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+// State shared by every client thread
+struct Server {
+    // swapped as a whole on reload, readers keep the snapshot they took
+    config: RwLock<Arc<Config>>,
+    // where the config came from, None when running on defaults
+    config_path: Option<String>,
+    started: Instant,
+    // read for lookups, relaying and \list, written only to join, leave, kick or
+    // change a user, so the room's threads relay side by side
+    clients: RwLock<HashMap<String, Client>>,
+    moderation: Mutex<Moderation>,
+    votes: Mutex<VoteKick>,
+    audit: AuditLog,
+    // recent relayed messages, captured into reports
+    history: Mutex<MessageBuffer>,
+    reports: Mutex<ReportQueue>,
+    // clients waiting for a free slot
+    waiting: WaitingQueue,
+    handshakes: Handshakes,
+    transcript: Transcript,
+    // set once a shutdown starts, new connections are turned away from then on
+    shutting_down: AtomicBool,
+    // clones of the accept loops' sockets by configured address, for a handoff
+    listeners: Vec<(String, Listener)>,
+    // systemd opened the listeners, handed on so a new process does not bind its own
+    socket_activated: bool,
+    next_client_id: AtomicU64,
+    // set while the sessions are being handed to a new process
+    handing_off: AtomicBool,
+    // unread input of client threads that stopped for a handoff, by connection id
+    parked: Mutex<HashMap<u64, Unread>>,
+}
+
+impl Server {
+    fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    // audit an action the server took on its own against a connected user
+    fn audit_automatic(&self, action: &str, stream: &Stream, nickname: &str, reason: &str) {
+        let target_addr = stream.peer_addr().to_string();
+        self.audit.record(AuditEntry {
+            action,
+            actor: "server",
+            actor_addr: None,
+            target: Some(nickname),
+            target_addr: Some(&target_addr),
+            reason,
+        });
+    }
+}
+
+// write a message to a socket, counting what goes out
+fn send_to(stream: &Stream, message: &str) -> io::Result<()> {
+    let mut stream = stream;
+    stream.write_all(message.as_bytes())?;
+    stream.flush()?;
+    METRICS.bytes_out(message.len());
+    Ok(())
+}
+
+// address of a connected user as "ip:port"
+fn address_of(clients: &HashMap<String, Client>, nickname: &str) -> Option<String> {
+    clients
+        .get(nickname)
+        .map(|client| format!("{}:{}", client.ip, client.port))
+}
+
+// frame user-written text so clients can tell it apart from server notices
+fn user_message(text: &str) -> String {
+    format!("{}{}\n", EVT_MESSAGE as char, text)
+}
+
+// label for a command byte in the metrics
+fn command_name(cmd: u8) -> &'static str {
+    match cmd {
+        CMD_LIST => "list",
+        CMD_TO => "to",
+        CMD_EXCEPT => "except",
+        CMD_BAN => "ban",
+        CMD_PING => "ping",
+        CMD_EXIT => "exit",
+        CMD_CHAT => "chat",
+        CMD_OPER => "oper",
+        CMD_VOTEKICK => "votekick",
+        CMD_AUDIT => "audit",
+        CMD_REPORT => "report",
+        CMD_REPORTS => "reports",
+        CMD_SHADOWBAN => "shadowban",
+        CMD_HEALTH => "health",
+        _ => "unknown",
     }
 }
 
-// Helper function to check for prohibited message content
-fn contains_prohibited_content(content: &str) -> bool {
-    content.to_lowercase().contains("i hate professor")
+// the filter policy frame clients receive during the handshake and after a change
+fn policy_frame(config: &Config) -> String {
+    format!("{}{}\n", EVT_POLICY as char, config.filter.encode())
+}
+
+// Re-read the config file and swap it in, an invalid file leaves the running
+// config alone. Settings only used at startup keep their old values until a restart.
+fn reload_config(server: &Server) -> Result<String, String> {
+    let path = server
+        .config_path
+        .as_deref()
+        .ok_or("the server was started without a config file")?;
+    let config = Config::load(path)?;
+
+    let old_config = server.config();
+    let version = config.filter.version();
+    let policy_changed = version != old_config.filter.version();
+    let restart_required = old_config.restart_required(&config);
+    let policy = policy_frame(&config);
+    logging::configure(&config);
+    *server.config.write().unwrap() = Arc::new(config);
+
+    let mut clients_lock = server.clients.write().unwrap();
+
+    // connected clients warn against the new phrases from now on
+    if policy_changed {
+        broadcast_to_all(&clients_lock, &policy, None);
+    }
+
+    // users the new ban lists cover leave now
+    let config = server.config();
+    let banned: Vec<String> = clients_lock
+        .values()
+        .filter(|client| config.is_banned(&client.nickname, &client.ip))
+        .map(|client| client.nickname.clone())
+        .collect();
+    for nickname in &banned {
+        ban_user(server, &mut clients_lock, "config", nickname, "ban list");
+    }
+
+    let mut reply = format!(
+        "Reloaded {} (filter policy version {:016x}, {} users banned)",
+        path,
+        version,
+        banned.len()
+    );
+    if !restart_required.is_empty() {
+        reply.push_str(&format!(
+            "\nRestart the server to apply: {}",
+            restart_required.join(", ")
+        ));
+    }
+    log_info!("Config reloaded: {}", reply.replace('\n', ". "));
+    Ok(reply)
 }
 
 // send a message to all clients
 fn broadcast_to_all(clients: &HashMap<String, Client>, message: &str, except: Option<&str>) {
+    let started = Instant::now();
     for (nickname, client) in clients.iter() {
         if let Some(except_nick) = except {
             if nickname == except_nick {
@@ -70,66 +311,1596 @@ fn broadcast_to_all(clients: &HashMap<String, Client>, message: &str, except: Op
         }
 
         if let Err(e) = client.send_message(message) {
-            eprintln!("Error broadcasting to {}: {}", nickname, e);
+            log_warn!("Error broadcasting to {}: {}", nickname, e);
         }
     }
+    METRICS.broadcast_latency(started.elapsed());
 }
 
-// Handle user disconnection due to prohibited content
-fn disconnect_for_prohibited_content(
-    stream: &TcpStream,
+// broadcast a server notice and keep it in the room transcript
+fn announce(
+    server: &Server,
+    clients: &HashMap<String, Client>,
+    notice: &str,
+    except: Option<&str>,
+) {
+    broadcast_to_all(clients, notice, except);
+    server.transcript.event(notice);
+}
+
+// Send to a user in the room through its entry, whose lock keeps the message out
+// of one being relayed to it. Nothing to do once the user left.
+fn send_to_member(server: &Server, nickname: &str, message: &str) -> io::Result<()> {
+    match server.clients.read().unwrap().get(nickname) {
+        Some(client) => client.send_message(message),
+        None => Ok(()),
+    }
+}
+
+// Handle user disconnection by the server, `reason` completes "removed for ..."
+fn disconnect_client(
     nickname: &str,
-    clients: &Arc<Mutex<HashMap<String, Client>>>,
+    server: &Server,
+    notice: &str,
+    reason: &str,
 ) -> io::Result<()> {
-    // Notify the client being disconnected
-    let mut banned_stream = stream.try_clone()?;
-    banned_stream
-        .write_all("You sent a prohibited message and will be disconnected.\n".as_bytes())?;
-    banned_stream.flush()?;
-
-    // Notify other clients and remove from client list
+    // Remove from client list, notify the client through its entry and then the others
     {
-        let mut clients_lock = clients.lock().unwrap();
-        let num_remaining = clients_lock.len() - 1;
+        let mut clients_lock = server.clients.write().unwrap();
+        if let Some(client) = clients_lock.remove(nickname) {
+            let _ = client.send_message(notice);
+        }
+        server.votes.lock().unwrap().cancel_for(nickname);
+        let num_remaining = clients_lock.len();
 
         // Message for other clients
         let notify_msg = format!(
-            "[{} was removed for prohibited message. {} users remain.]\n",
-            nickname, num_remaining
+            "[{} was removed for {}. {} users remain.]\n",
+            nickname, reason, num_remaining
+        );
+        announce(server, &clients_lock, &notify_msg, None);
+
+        log_info!(
+            "{} is removed for {}. There are {} users now",
+            nickname,
+            reason,
+            num_remaining
         );
+    }
+
+    Ok(())
+}
+
+// Handle user disconnection due to prohibited content
+fn disconnect_for_prohibited_content(nickname: &str, server: &Server) -> io::Result<()> {
+    disconnect_client(
+        nickname,
+        server,
+        "You sent a prohibited message and will be disconnected.\n",
+        "prohibited message",
+    )
+}
+
+// Remove a user for an offense, prohibited content keeps its own notice
+fn disconnect_for_offense(nickname: &str, server: &Server, offense: Offense) -> io::Result<()> {
+    match offense {
+        Offense::ProhibitedContent => disconnect_for_prohibited_content(nickname, server),
+        _ => disconnect_client(
+            nickname,
+            server,
+            "You were spamming and will be disconnected.\n",
+            "spamming",
+        ),
+    }
+}
+
+// Give a strike for a blocked message, returns true if the user was kicked
+fn strike_for_offense(
+    stream: &Stream,
+    nickname: &str,
+    server: &Server,
+    offense: Offense,
+) -> io::Result<bool> {
+    let config = server.config();
+    let (strikes, action) = server
+        .moderation
+        .lock()
+        .unwrap()
+        .add_strike(nickname, &config);
 
-        // Send to all other clients
-        for (nick, client) in clients_lock.iter() {
-            if *nick != nickname {
-                let _ = client.send_message(&notify_msg);
+    log_info!(
+        "{} was blocked for {} (strike {} of {})",
+        nickname,
+        offense.describe(),
+        strikes,
+        config.strike_kick_threshold
+    );
+
+    let audit_action = match action {
+        StrikeAction::Warn => "warn",
+        StrikeAction::Mute(_) => "mute",
+        StrikeAction::Kick => "kick",
+    };
+    let reason = format!(
+        "{} (strike {} of {})",
+        offense.describe(),
+        strikes,
+        config.strike_kick_threshold
+    );
+    server.audit_automatic(audit_action, stream, nickname, &reason);
+
+    let notice = match action {
+        StrikeAction::Kick => {
+            disconnect_for_offense(nickname, server, offense)?;
+            return Ok(true);
+        }
+        StrikeAction::Mute(duration) => {
+            log_info!("{} is muted for {}s", nickname, duration.as_secs());
+            format!(
+                "Warning: your message was blocked for {} and you are muted for {}s (strike {} of {}).\n",
+                offense.describe(),
+                duration.as_secs(),
+                strikes,
+                config.strike_kick_threshold
+            )
+        }
+        StrikeAction::Warn => format!(
+            "Warning: your message was blocked for {} (strike {} of {}).\n",
+            offense.describe(),
+            strikes,
+            config.strike_kick_threshold
+        ),
+    };
+
+    send_to_member(server, nickname, &notice)?;
+
+    Ok(false)
+}
+
+// Apply the configured spam action, returns true if the user was kicked
+fn act_on_spam(
+    stream: &Stream,
+    nickname: &str,
+    server: &Server,
+    offense: Offense,
+) -> io::Result<bool> {
+    let config = server.config();
+
+    let notice = match config.spam_action {
+        SpamAction::Strike => return strike_for_offense(stream, nickname, server, offense),
+        SpamAction::Kick => {
+            log_info!("{} was blocked for {}", nickname, offense.describe());
+            server.audit_automatic("kick", stream, nickname, offense.describe());
+            disconnect_for_offense(nickname, server, offense)?;
+            return Ok(true);
+        }
+        SpamAction::Mute => {
+            server.audit_automatic("mute", stream, nickname, offense.describe());
+            server
+                .moderation
+                .lock()
+                .unwrap()
+                .mute(nickname, config.mute_duration);
+            log_info!(
+                "{} was blocked for {} and muted for {}s",
+                nickname,
+                offense.describe(),
+                config.mute_duration.as_secs()
+            );
+            format!(
+                "Warning: your message was blocked for {} and you are muted for {}s.\n",
+                offense.describe(),
+                config.mute_duration.as_secs()
+            )
+        }
+        SpamAction::Warn => {
+            log_info!("{} was blocked for {}", nickname, offense.describe());
+            server.audit_automatic("warn", stream, nickname, offense.describe());
+            format!(
+                "Warning: your message was blocked for {}.\n",
+                offense.describe()
+            )
+        }
+    };
+
+    send_to_member(server, nickname, &notice)?;
+
+    Ok(false)
+}
+
+// Tell a muted user how long the mute lasts, returns true if the user is muted
+fn reject_if_muted(nickname: &str, server: &Server) -> bool {
+    let remaining = match server.moderation.lock().unwrap().muted_for(nickname) {
+        Some(remaining) => remaining,
+        None => return false,
+    };
+
+    if let Some(client) = server.clients.read().unwrap().get(nickname) {
+        let error_msg = format!(
+            "Error: You are muted for another {}s.\n",
+            remaining.as_secs() + 1
+        );
+        let _ = client.send_message(&error_msg);
+    }
+    true
+}
+
+// Remove a banned user from the room and tell everyone, `banned_by` must not be the user
+fn ban_user(
+    server: &Server,
+    clients_lock: &mut HashMap<String, Client>,
+    banned_by: &str,
+    ban_nick: &str,
+    reason: &str,
+) {
+    server.audit.record(AuditEntry {
+        action: "ban",
+        actor: banned_by,
+        actor_addr: address_of(clients_lock, banned_by).as_deref(),
+        target: Some(ban_nick),
+        target_addr: address_of(clients_lock, ban_nick).as_deref(),
+        reason,
+    });
+
+    // ban the user and remove them from the client list
+    if let Some(client) = clients_lock.remove(ban_nick) {
+        let ban_msg = format!("you are banned by {}\n", banned_by);
+        client.disconnect(&ban_msg);
+    }
+    server.votes.lock().unwrap().cancel_for(ban_nick);
+
+    // broadcast the ban message to all clients
+    let ban_broadcast = format!(
+        "[{} left the room. There are {} users now]\n",
+        ban_nick,
+        clients_lock.len()
+    );
+
+    log_info!(
+        "{} was banned by {}. There are {} users now",
+        ban_nick,
+        banned_by,
+        clients_lock.len()
+    );
+
+    announce(server, clients_lock, &ban_broadcast, None);
+}
+
+// Handle \oper: become an operator with the configured password, returns true once
+// the user gave too many wrong passwords and was removed
+fn handle_oper(
+    stream: &Stream,
+    nickname: &str,
+    password: &str,
+    server: &Server,
+    failures: &mut usize,
+) -> bool {
+    let config = server.config();
+
+    let (reply, audit_action) = if config.operator_password.is_empty() {
+        ("Error: Operators are disabled on this server.\n", None)
+    } else if !admin::tokens_match(password.as_bytes(), config.operator_password.as_bytes()) {
+        log_warn!("{} failed to become an operator", nickname);
+        *failures += 1;
+        ("Error: Wrong operator password.\n", Some("oper_failed"))
+    } else {
+        // only a granted \oper changes the registry
+        match server.clients.write().unwrap().get_mut(nickname) {
+            Some(client) => client.operator = true,
+            None => return false,
+        }
+        log_info!("{} is now an operator", nickname);
+        ("[You are now an operator.]\n", Some("oper"))
+    };
+    let _ = send_to_member(server, nickname, reply);
+
+    if let Some(action) = audit_action {
+        let actor_addr = stream.peer_addr().to_string();
+        server.audit.record(AuditEntry {
+            action,
+            actor: nickname,
+            actor_addr: Some(&actor_addr),
+            target: None,
+            target_addr: None,
+            reason: "\\oper",
+        });
+    }
+
+    let limit = config.oper_failure_limit;
+    if limit > 0 && *failures >= limit {
+        server.audit_automatic("kick", stream, nickname, "wrong operator passwords");
+        let _ = disconnect_client(
+            nickname,
+            server,
+            "You gave too many wrong operator passwords and will be disconnected.\n",
+            "wrong operator passwords",
+        );
+        return true;
+    }
+    false
+}
+
+// Handle \audit [nickname] [count]: show recent audit entries to an operator
+fn handle_audit(nickname: &str, args: &str, server: &Server) {
+    let mut filter_nick = None;
+    let mut count = 10;
+    for arg in args.split_whitespace() {
+        match arg.parse::<usize>() {
+            Ok(n) => count = n.clamp(1, 50),
+            Err(_) => filter_nick = Some(arg),
+        }
+    }
+
+    // the query reads the log files, so it runs without the registry lock
+    let is_operator = server
+        .clients
+        .read()
+        .unwrap()
+        .get(nickname)
+        .is_some_and(|client| client.operator);
+    let reply = if !is_operator {
+        "Error: Only operators can read the audit log.\n".to_string()
+    } else {
+        match server.audit.query(filter_nick, count) {
+            Ok(entries) if entries.is_empty() => "[audit] no matching entries\n".to_string(),
+            Ok(entries) => entries
+                .iter()
+                .map(|entry| format!("[audit] {}\n", entry))
+                .collect(),
+            Err(e) => {
+                log_error!("Error reading audit log: {}", e);
+                "Error: The audit log cannot be read.\n".to_string()
             }
         }
+    };
+
+    if let Some(client) = server.clients.read().unwrap().get(nickname) {
+        let _ = client.send_message(&reply);
+    }
+}
 
-        // Remove from client list
-        clients_lock.remove(nickname);
+// Handle \votekick: open or join a timed vote to kick a user
+fn handle_votekick(nickname: &str, target: &str, server: &Arc<Server>) {
+    let config = server.config();
+    // read only, a vote that passes takes the write lock to remove its target
+    let clients_lock = server.clients.read().unwrap();
 
-        println!(
-            "{} is removed for sending prohibited message. There are {} users now",
-            nickname, num_remaining
+    let error_msg = if target == nickname {
+        Some("Error: You cannot vote to kick yourself.\n".to_string())
+    } else if !clients_lock.contains_key(target) {
+        Some(format!("Error: User '{}' does not exist.\n", target))
+    } else if clients_lock.values().any(|client| client.operator) {
+        Some("Error: An operator is online, ask them instead.\n".to_string())
+    } else {
+        None
+    };
+    if let Some(error_msg) = error_msg {
+        if let Some(client) = clients_lock.get(nickname) {
+            let _ = client.send_message(&error_msg);
+        }
+        return;
+    }
+
+    // everyone but the target gets a say
+    let eligible = clients_lock.len() - 1;
+    let needed = votekick::needed_votes(
+        eligible,
+        config.votekick_fraction,
+        config.votekick_min_votes,
+    );
+    let outcome = server
+        .votes
+        .lock()
+        .unwrap()
+        .vote(nickname, target, eligible, needed);
+
+    match outcome {
+        VoteOutcome::Opened { id, votes, needed } => {
+            let msg = format!(
+                "[{} started a vote to kick {} ({}/{} votes, {}s left). Type \\votekick {} to agree.]\n",
+                nickname,
+                target,
+                votes,
+                needed,
+                config.votekick_duration.as_secs(),
+                target
+            );
+            announce(server, &clients_lock, &msg, None);
+            log_info!("{} started a vote to kick {}", nickname, target);
+
+            // close the vote when time runs out
+            let server_clone = Arc::clone(server);
+            let duration = config.votekick_duration;
+            thread::spawn(move || {
+                thread::sleep(duration);
+                let clients_lock = server_clone.clients.read().unwrap();
+                if let Some(target) = server_clone.votes.lock().unwrap().expire(id) {
+                    let msg = format!("[The vote to kick {} failed.]\n", target);
+                    announce(&server_clone, &clients_lock, &msg, None);
+                    log_info!("The vote to kick {} failed", target);
+                }
+            });
+        }
+        VoteOutcome::Counted { votes, needed } => {
+            let msg = format!(
+                "[{} voted to kick {} ({}/{} votes).]\n",
+                nickname, target, votes, needed
+            );
+            announce(server, &clients_lock, &msg, None);
+        }
+        VoteOutcome::Passed { needed } => {
+            drop(clients_lock);
+            let mut clients_lock = server.clients.write().unwrap();
+            let target_addr = address_of(&clients_lock, target);
+            if let Some(client) = clients_lock.remove(target) {
+                client.disconnect("You were kicked by a vote of the room.\n");
+            }
+
+            let reason = format!("{} votes needed, last vote by {}", needed, nickname);
+            server.audit.record(AuditEntry {
+                action: "votekick",
+                actor: "vote",
+                actor_addr: None,
+                target: Some(target),
+                target_addr: target_addr.as_deref(),
+                reason: &reason,
+            });
+
+            let msg = format!(
+                "[The vote to kick {} passed. {} was removed. {} users remain.]\n",
+                target,
+                target,
+                clients_lock.len()
+            );
+            announce(server, &clients_lock, &msg, None);
+            log_info!(
+                "{} was kicked by vote. There are {} users now",
+                target,
+                clients_lock.len()
+            );
+        }
+        VoteOutcome::TooFewVoters { needed } => {
+            if let Some(client) = clients_lock.get(nickname) {
+                let error_msg = format!(
+                    "Error: Not enough users for a vote, it needs {} votes.\n",
+                    needed
+                );
+                let _ = client.send_message(&error_msg);
+            }
+        }
+        VoteOutcome::AlreadyVoted => {
+            if let Some(client) = clients_lock.get(nickname) {
+                let error_msg = format!("Error: You already voted to kick {}.\n", target);
+                let _ = client.send_message(&error_msg);
+            }
+        }
+        VoteOutcome::Busy(running) => {
+            if let Some(client) = clients_lock.get(nickname) {
+                let error_msg = format!("Error: A vote to kick {} is already running.\n", running);
+                let _ = client.send_message(&error_msg);
+            }
+        }
+    }
+}
+
+// Handle \report <nickname> <reason>: queue a report with the user's recent messages
+fn handle_report(nickname: &str, content: &str, server: &Server) {
+    let clients_lock = server.clients.read().unwrap();
+    let (target, reason) = content.split_once(' ').unwrap_or((content, ""));
+    let reason = reason.trim();
+
+    let reply = if target == nickname {
+        "Error: You cannot report yourself.\n".to_string()
+    } else if reason.is_empty() {
+        "Error: Please give a reason for the report.\n".to_string()
+    } else {
+        let messages = server.history.lock().unwrap().recent_from(
+            target,
+            nickname,
+            server.config().report_context,
+        );
+
+        if messages.is_empty() && !clients_lock.contains_key(target) {
+            format!("Error: User '{}' does not exist.\n", target)
+        } else {
+            let filed = server.reports.lock().unwrap().file(
+                &server.config(),
+                nickname,
+                target,
+                reason,
+                messages,
+            );
+            match filed {
+                Err(e) => format!("Error: {}\n", e),
+                Ok(id) => {
+                    server.audit.record(AuditEntry {
+                        action: "report",
+                        actor: nickname,
+                        actor_addr: address_of(&clients_lock, nickname).as_deref(),
+                        target: Some(target),
+                        target_addr: address_of(&clients_lock, target).as_deref(),
+                        reason,
+                    });
+                    log_info!("{} reported {} (report #{})", nickname, target, id);
+
+                    // let the operators online know
+                    let notice = format!(
+                        "[New report #{} against {}. Type \\reports to review.]\n",
+                        id, target
+                    );
+                    for client in clients_lock.values().filter(|client| client.operator) {
+                        let _ = client.send_message(&notice);
+                    }
+
+                    format!(
+                        "[Report #{} against {} was sent to the operators.]\n",
+                        id, target
+                    )
+                }
+            }
+        }
+    };
+
+    if let Some(client) = clients_lock.get(nickname) {
+        let _ = client.send_message(&reply);
+    }
+}
+
+// Apply an operator's decision on a report, returns the confirmation to show them
+fn resolve_report(
+    server: &Server,
+    id: u64,
+    resolution: Resolution,
+    resolved_by: &str,
+) -> Result<String, String> {
+    let mut clients_lock = server.clients.write().unwrap();
+    let (target, reason) = {
+        let mut reports = server.reports.lock().unwrap();
+        // a warning or ban needs someone to act on, the report waits for them
+        if let Some(report) = reports.get(id) {
+            let acts_on_user = matches!(resolution, Resolution::Warn | Resolution::Ban);
+            if acts_on_user
+                && report.resolved.is_none()
+                && !clients_lock.contains_key(&report.target)
+            {
+                return Err(format!(
+                    "{} is not connected, report #{} stays open.",
+                    report.target, id
+                ));
+            }
+        }
+        let report = reports.resolve(id, resolution, resolved_by)?;
+        (report.target, report.reason)
+    };
+    let audit_reason = format!("report #{}: {}", id, reason);
+
+    match resolution {
+        Resolution::Dismiss => {}
+        Resolution::Warn => {
+            if let Some(client) = clients_lock.get(&target) {
+                let warning = format!("Warning from the operators: {}\n", reason);
+                let _ = client.send_message(&warning);
+            }
+        }
+        Resolution::Mute => {
+            let duration = server.config().mute_duration;
+            server.moderation.lock().unwrap().mute(&target, duration);
+            if let Some(client) = clients_lock.get(&target) {
+                let notice = format!(
+                    "You were muted for {}s by the operators: {}\n",
+                    duration.as_secs(),
+                    reason
+                );
+                let _ = client.send_message(&notice);
+            }
+        }
+        Resolution::Ban => {
+            ban_user(
+                server,
+                &mut clients_lock,
+                resolved_by,
+                &target,
+                &audit_reason,
+            );
+        }
+    }
+
+    // ban_user audits the ban itself
+    if !matches!(resolution, Resolution::Ban) {
+        server.audit.record(AuditEntry {
+            action: &format!("report_{}", resolution.name()),
+            actor: resolved_by,
+            actor_addr: address_of(&clients_lock, resolved_by).as_deref(),
+            target: Some(&target),
+            target_addr: address_of(&clients_lock, &target).as_deref(),
+            reason: &audit_reason,
+        });
+    }
+    log_info!(
+        "Report #{} against {} resolved by {}: {}",
+        id,
+        target,
+        resolved_by,
+        resolution.name()
+    );
+
+    Ok(format!(
+        "[Report #{} resolved: {} {}.]\n",
+        id,
+        resolution.name(),
+        target
+    ))
+}
+
+// Handle \reports [show <id> | resolve <id> <dismiss|warn|mute|ban>] for operators
+fn handle_reports(nickname: &str, args: &str, server: &Server) {
+    let is_operator = server
+        .clients
+        .read()
+        .unwrap()
+        .get(nickname)
+        .is_some_and(|client| client.operator);
+
+    let reply = if !is_operator {
+        "Error: Only operators can review reports.\n".to_string()
+    } else {
+        review_reports(server, nickname, "\\reports", args)
+    };
+
+    if let Some(client) = server.clients.read().unwrap().get(nickname) {
+        let _ = client.send_message(&reply);
+    }
+}
+
+// List, show or resolve reports on behalf of `actor`, shared by \reports and the
+// console. `command` is how the caller spells it, for the usage lines.
+fn review_reports(server: &Server, actor: &str, command: &str, args: &str) -> String {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    match args.as_slice() {
+        [] => {
+            let reports = server.reports.lock().unwrap();
+            let listing: String = reports
+                .open_reports()
+                .map(|report| format!("{}\n", report.summary()))
+                .collect();
+            if listing.is_empty() {
+                "[No open reports.]\n".to_string()
+            } else {
+                listing
+            }
+        }
+        ["show", id] => {
+            let reports = server.reports.lock().unwrap();
+            match id.parse().ok().and_then(|id| reports.get(id)) {
+                Some(report) => {
+                    let mut detail = format!("{}\n", report.summary());
+                    for message in &report.messages {
+                        detail.push_str(&format!("  {}\n", message));
+                    }
+                    detail
+                }
+                None => format!("Error: Report #{} does not exist.\n", id),
+            }
+        }
+        ["resolve", id, action] => match (id.parse(), Resolution::parse(action)) {
+            (Ok(id), Some(resolution)) => resolve_report(server, id, resolution, actor)
+                .unwrap_or_else(|e| format!("Error: {}\n", e)),
+            _ => format!("Usage: {} resolve <id> <dismiss|warn|mute|ban>\n", command),
+        },
+        _ => format!(
+            "Usage: {} [show <id> | resolve <id> <dismiss|warn|mute|ban>]\n",
+            command
+        ),
+    }
+}
+
+// Handle \shadowban [<nickname> [off]]: list, set or lift shadow-bans, operators only
+fn handle_shadowban(nickname: &str, args: &str, server: &Server) {
+    let clients_lock = server.clients.read().unwrap();
+    let client = match clients_lock.get(nickname) {
+        Some(client) => client,
+        None => return,
+    };
+    if !client.operator {
+        let _ = client.send_message("invalid command\n");
+        return;
+    }
+
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (target, banned) = match args.as_slice() {
+        [] => {
+            let listing = server.moderation.lock().unwrap().shadow_banned().join(", ");
+            let reply = format!("[Shadow-banned: {}]\n", listing);
+            let _ = client.send_message(&reply);
+            return;
+        }
+        [target] => (*target, true),
+        [target, "off"] => (*target, false),
+        _ => {
+            let _ = client.send_message("Usage: \\shadowban [<nickname> [off]]\n");
+            return;
+        }
+    };
+
+    let changed = server
+        .moderation
+        .lock()
+        .unwrap()
+        .set_shadow_ban(target, banned);
+    let reply = match (changed, banned) {
+        (true, true) => format!("[{} is now shadow-banned.]\n", target),
+        (true, false) => format!("[{} is no longer shadow-banned.]\n", target),
+        (false, true) => format!("Error: {} is already shadow-banned.\n", target),
+        (false, false) => format!("Error: {} is not shadow-banned.\n", target),
+    };
+    let _ = client.send_message(&reply);
+
+    if changed {
+        server.audit.record(AuditEntry {
+            action: if banned { "shadowban" } else { "unshadowban" },
+            actor: nickname,
+            actor_addr: address_of(&clients_lock, nickname).as_deref(),
+            target: Some(target),
+            target_addr: address_of(&clients_lock, target).as_deref(),
+            reason: "\\shadowban",
+        });
+        log_info!(
+            "{} {} {}",
+            nickname,
+            if banned {
+                "shadow-banned"
+            } else {
+                "lifted the shadow-ban on"
+            },
+            target
         );
     }
+}
+
+// A shadow-banned user's chat, \to and \except look delivered to them but reach nobody,
+// returns true if the frame was swallowed
+fn swallow_if_shadow_banned(cmd: u8, content: &str, nickname: &str, server: &Server) -> bool {
+    if !server.moderation.lock().unwrap().is_shadow_banned(nickname) {
+        return false;
+    }
+
+    // frames the normal path answers with an error still get that error
+    if cmd != CMD_CHAT {
+        let target = match content.split_once(' ') {
+            Some((target, _)) => target,
+            None => return false,
+        };
+        if target == nickname || !server.clients.read().unwrap().contains_key(target) {
+            return false;
+        }
+    }
+
+    log_debug!(
+        "{} (shadow-banned): cmd={}, content={}",
+        nickname,
+        cmd,
+        logging::content(content)
+    );
+    true
+}
+
+// Answer a malformed frame, returns true once the peer sent too many and was removed
+fn reject_invalid_frame(
+    stream: &Stream,
+    nickname: &str,
+    server: &Server,
+    violations: &mut usize,
+    error_msg: &str,
+) -> io::Result<bool> {
+    *violations += 1;
+    let limit = server.config().frame_violation_limit;
+
+    if limit > 0 && *violations >= limit {
+        server.audit_automatic("kick", stream, nickname, "invalid messages");
+        disconnect_client(
+            nickname,
+            server,
+            "You sent too many invalid messages and will be disconnected.\n",
+            "invalid messages",
+        )?;
+        return Ok(true);
+    }
+
+    if let Some(client) = server.clients.read().unwrap().get(nickname) {
+        let _ = client.send_message(error_msg);
+    }
+    Ok(false)
+}
+
+// Tell a rejected connection why and log it
+// `kind` labels the rejection in the metrics, `reason` is the detail for the log
+fn reject_connection(
+    id: u64,
+    stream: &Stream,
+    client_addr: &PeerAddr,
+    error_msg: &str,
+    kind: &'static str,
+    reason: &str,
+) {
+    let _ = send_to(stream, error_msg);
+    METRICS.rejected(kind);
+    log_info!(
+        conn = id;
+        "Connection from {}:{} rejected: {}",
+        client_addr.ip(),
+        client_addr.port(),
+        reason
+    );
+}
 
+// Hold a queued client until a slot frees up, returns false if it gave up waiting
+fn wait_for_slot(stream: &Stream, ticket: u64, nickname: &str, id: u64, server: &Server) -> bool {
+    let mut last_position = 0;
+
+    loop {
+        let position = match server.waiting.position(ticket) {
+            Some(position) => position,
+            None => return false,
+        };
+
+        // the head of the queue takes the first free slot
+        if position == 1 {
+            let mut clients_lock = server.clients.write().unwrap();
+            if clients_lock.len() < server.config().max_clients {
+                if let Ok(client_stream) = stream.try_clone() {
+                    let client = Client::new(id, nickname.to_string(), client_stream);
+                    clients_lock.insert(nickname.to_string(), client);
+                    server.waiting.leave(ticket);
+                    return true;
+                }
+            }
+        }
+
+        if position != last_position {
+            let notice = format!(
+                "{}The room is full. You are number {} in the waiting queue.\n",
+                EVT_QUEUE as char, position
+            );
+            if send_to(stream, &notice).is_err() {
+                break;
+            }
+            last_position = position;
+        }
+
+        server.waiting.wait(Duration::from_secs(1));
+        if !stream.peer_connected() {
+            break;
+        }
+    }
+
+    server.waiting.leave(ticket);
+    log_info!(conn = id; "{} left the waiting queue", nickname);
+    false
+}
+
+// Ask the challenge until it is answered or the attempts run out
+fn pass_challenge(
+    stream: &Stream,
+    reader: &mut BufReader<Stream>,
+    challenge: &Challenge,
+    attempts: usize,
+) -> io::Result<bool> {
+    let mut answer = Vec::new();
+
+    for attempt in 0..attempts {
+        let prompt = if attempt == 0 {
+            format!("{}{}\n", EVT_CHALLENGE as char, challenge.prompt)
+        } else {
+            format!(
+                "{}Wrong answer. {}\n",
+                EVT_CHALLENGE as char, challenge.prompt
+            )
+        };
+        send_to(stream, &prompt)?;
+
+        match frame::read_frame(reader, &mut answer, 64) {
+            Ok(Frame::Line) => {
+                if challenge.check(&String::from_utf8_lossy(&answer)) {
+                    return Ok(true);
+                }
+            }
+            Ok(Frame::TooLong) => {}
+            Ok(Frame::Eof) => return Ok(false),
+            // the handshake timeout covers the answer too
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+// Run the handshake for a new connection, then serve it until it leaves
+fn admit_client(mut stream: Stream, id: u64, server: &Arc<Server>) -> io::Result<()> {
+    let config = server.config();
+
+    // the whole handshake, PROXY header to nickname, has to arrive in time
+    stream.set_read_timeout(Some(config.handshake_timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    // behind a load balancer the client's own address comes first
+    if config.expects_proxy_header(stream.peer_addr().ip()) {
+        match proxy::read_header(&mut reader) {
+            Ok(Some(addr)) => {
+                log_debug!(conn = id; "{} is a proxy for {}", stream.peer_addr(), addr);
+                stream.set_peer_addr(addr.into());
+            }
+            // the proxy's own connection, e.g. its health check
+            Ok(None) => {}
+            Err(e) => {
+                METRICS.rejected("proxy_header");
+                log_info!(
+                    conn = id;
+                    "Connection from {} closed: bad PROXY header: {}",
+                    stream.peer_addr(),
+                    e
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    let client_addr = stream.peer_addr().clone();
+    let ip = client_addr.ip().to_string();
+
+    // read the nickname from the client, anything but a short UTF-8 line is invalid
+    let mut nickname_frame = Vec::new();
+    let nickname = match frame::read_frame(&mut reader, &mut nickname_frame, 64) {
+        Ok(Frame::Line) => {
+            METRICS.bytes_in(nickname_frame.len() + 1);
+            // a health check, it takes no slot and is answered even while shutting down
+            if nickname_frame.first() == Some(&CMD_HEALTH) {
+                METRICS.command(command_name(CMD_HEALTH));
+                log_debug!(conn = id; "Health check from {}", client_addr);
+                let _ = send_to(&stream, &Health::check(server).line());
+                return Ok(());
+            }
+            String::from_utf8_lossy(&nickname_frame).trim().to_string()
+        }
+        Ok(Frame::TooLong) => String::from("<too long>"),
+        Ok(Frame::Eof) => return Ok(()),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            METRICS.rejected("handshake_timeout");
+            log_info!(
+                conn = id;
+                "Connection from {}:{} closed: no nickname within {}s",
+                client_addr.ip(),
+                client_addr.port(),
+                config.handshake_timeout.as_secs()
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    // only logged now, health checks come often and stay out of the info log
+    log_info!(
+        conn = id;
+        "New connection from {}:{}",
+        client_addr.ip(),
+        client_addr.port()
+    );
+
+    if server.shutting_down.load(Ordering::SeqCst) {
+        let error_msg = "server is shutting down. cannot connect\n";
+        reject_connection(
+            id,
+            &stream,
+            &client_addr,
+            error_msg,
+            "shutting_down",
+            "shutting down",
+        );
+        return Ok(());
+    }
+    if server.handing_off.load(Ordering::SeqCst) {
+        let error_msg = "server is restarting. please reconnect\n";
+        reject_connection(
+            id,
+            &stream,
+            &client_addr,
+            error_msg,
+            "restarting",
+            "restarting",
+        );
+        return Ok(());
+    }
+
+    // check the nickname format
+    if nickname.is_empty()
+        || nickname.len() > 10
+        || nickname.contains(|c: char| !c.is_ascii_alphanumeric())
+    {
+        let error_msg =
+            "nickname must be <= 10 characters, English only, no spaces or special chars\n";
+        let reason = format!("invalid nickname format '{}'", nickname);
+        reject_connection(
+            id,
+            &stream,
+            &client_addr,
+            error_msg,
+            "invalid_nickname",
+            &reason,
+        );
+        return Ok(());
+    }
+
+    if config.is_banned(&nickname, &ip) {
+        let error_msg = "you are banned from this server. cannot connect\n";
+        let reason = format!("'{}' from {} is on the ban list", nickname, ip);
+        reject_connection(id, &stream, &client_addr, error_msg, "banned", &reason);
+        return Ok(());
+    }
+
+    // everyone proves they are not a script before taking a slot, a nickname is
+    // only a claim and cannot buy an exemption
+    if let Some(challenge) = Challenge::new(config.join_challenge) {
+        if !pass_challenge(
+            &stream,
+            &mut reader,
+            &challenge,
+            config.join_challenge_attempts,
+        )? {
+            let error_msg = "join challenge failed. cannot connect\n";
+            let reason = format!("'{}' failed the join challenge", nickname);
+            reject_connection(
+                id,
+                &stream,
+                &client_addr,
+                error_msg,
+                "challenge_failed",
+                &reason,
+            );
+            return Ok(());
+        }
+    }
+    // a read blocked for good would keep the thread from noticing a handoff
+    stream.set_read_timeout(Some(READ_POLL))?;
+
+    // the connection stays a handshake until it is in the room or the queue, both
+    // under this lock, so a handoff or shutdown always finds it in one of them
+    let mut clients_lock = server.clients.write().unwrap();
+
+    // check if the nickname is already in use, queued clients hold on to theirs
+    if clients_lock.contains_key(&nickname) || server.waiting.contains_nickname(&nickname) {
+        let error_msg = "nickname already used by another user. cannot connect\n";
+        let reason = format!("nickname '{}' already in use", nickname);
+        reject_connection(
+            id,
+            &stream,
+            &client_addr,
+            error_msg,
+            "nickname_in_use",
+            &reason,
+        );
+        return Ok(());
+    }
+
+    // one host cannot take every slot
+    let from_ip =
+        clients_lock.values().filter(|c| c.ip == ip).count() + server.waiting.count_from_ip(&ip);
+    if config.max_clients_per_ip > 0 && from_ip >= config.max_clients_per_ip {
+        let error_msg = "too many connections from your address. cannot connect\n";
+        let reason = format!(
+            "too many connections from {} (max {})",
+            ip, config.max_clients_per_ip
+        );
+        reject_connection(
+            id,
+            &stream,
+            &client_addr,
+            error_msg,
+            "per_ip_limit",
+            &reason,
+        );
+        return Ok(());
+    }
+
+    // check if the maximum number of clients is reached, waiting clients go first
+    if clients_lock.len() < config.max_clients && server.waiting.is_empty() {
+        // add the new client to the list
+        let client = Client::new(id, nickname.clone(), stream.try_clone()?);
+        clients_lock.insert(nickname.clone(), client);
+        server.handshakes.finish(id);
+        drop(clients_lock);
+    } else if server.waiting.len() < config.waiting_queue_size {
+        let ticket = server.waiting.join(&nickname, stream.try_clone()?);
+        server.handshakes.finish(id);
+        drop(clients_lock);
+        log_info!(
+            conn = id;
+            "Connection from {}:{} queued: chatting room full (max {} clients)",
+            client_addr.ip(),
+            client_addr.port(),
+            config.max_clients
+        );
+
+        if !wait_for_slot(&stream, ticket, &nickname, id, server) {
+            return Ok(());
+        }
+    } else {
+        let error_msg = "chatting room full. cannot connect\n";
+        let reason = format!("chatting room full (max {} clients)", config.max_clients);
+        reject_connection(id, &stream, &client_addr, error_msg, "room_full", &reason);
+        return Ok(());
+    }
+
+    welcome_client(&stream, id, &nickname, server)?;
+    if let Err(e) = handle_client(
+        stream,
+        reader,
+        Unread::default(),
+        id,
+        nickname.clone(),
+        Arc::clone(server),
+    ) {
+        log_error!(conn = id; "Error handling client {}: {}", nickname, e);
+    }
     Ok(())
 }
 
-// Process incoming messages from clients
-fn handle_client(
-    stream: TcpStream,
+// Start a graceful shutdown in the background, false if one is already running
+fn begin_shutdown(server: &Arc<Server>, initiator: &str, countdown: u64, reason: &str) -> bool {
+    if server.shutting_down.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    let server = Arc::clone(server);
+    let initiator = initiator.to_string();
+    let reason = reason.to_string();
+    thread::spawn(move || shutdown(&server, &initiator, countdown, &reason));
+    true
+}
+
+// Count down, then tell everyone, close every connection cleanly and exit
+fn shutdown(server: &Server, initiator: &str, countdown: u64, reason: &str) -> ! {
+    let reason = match reason {
+        "" => String::new(),
+        reason => format!(": {}", reason),
+    };
+    log_info!(
+        "Shutting down in {}s, requested by {}{}",
+        countdown,
+        initiator,
+        reason
+    );
+    systemd::notify("STOPPING=1");
+
+    for remaining in (1..=countdown).rev() {
+        if remaining == countdown || remaining <= 5 || remaining % 10 == 0 {
+            let notice = format!(
+                "[The server is shutting down in {} seconds{}.]\n",
+                remaining, reason
+            );
+            announce(server, &server.clients.read().unwrap(), &notice, None);
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    // taking everyone out of the map first keeps their threads from announcing each leave
+    let clients: Vec<Client> = {
+        let mut clients_lock = server.clients.write().unwrap();
+        server
+            .transcript
+            .event(&format!("[The server shut down{}.]", reason));
+        clients_lock.drain().map(|(_, client)| client).collect()
+    };
+    let notice = format!("[The server is shutting down{}.]\n", reason);
+    for client in &clients {
+        let _ = client.send_message(&notice);
+        // the peer reads everything sent so far, then sees the connection end
+        let _ = client.stream.shutdown(Shutdown::Write);
+    }
+    // those not in the room yet are told too, rather than just cut off
+    let waiting = server.waiting.dismiss_all(&notice);
+    let handshakes = server.handshakes.dismiss_all(&notice);
+
+    for (name, result) in [
+        ("audit log", server.audit.sync()),
+        ("transcript", server.transcript.sync()),
+        ("log file", logging::sync()),
+    ] {
+        if let Err(e) = result {
+            log_error!("Error syncing the {}: {}", name, e);
+        }
+    }
+
+    // give the clients a moment to read the notice and close their end
+    thread::sleep(SHUTDOWN_LINGER);
+    for path in server.listeners.iter().filter_map(|(_, l)| l.unix_path()) {
+        let _ = fs::remove_file(path);
+    }
+    let pid_file = &server.config().pid_file;
+    if !pid_file.is_empty() {
+        daemon::remove_pid_file(pid_file);
+    }
+    log_info!(
+        "Server shut down, {} users, {} waiting and {} connecting clients disconnected",
+        clients.len(),
+        waiting,
+        handshakes
+    );
+    process::exit(0);
+}
+
+// Reload the config on SIGHUP, hand off to a new process on SIGUSR2. Shut down
+// gracefully on SIGINT / SIGTERM, a second one exits at once.
+fn handle_signals(server: Arc<Server>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM, SIGUSR2])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                systemd::notify_reloading();
+                if let Err(e) = reload_config(&server) {
+                    log_error!("Config reload failed, keeping the running config: {}", e);
+                }
+                systemd::notify("READY=1");
+                continue;
+            }
+
+            if signal == SIGUSR2 {
+                if !begin_handoff(&server, &format!("signal {}", signal)) {
+                    log_warn!(
+                        "Signal {} ignored, a shutdown or handoff is running",
+                        signal
+                    );
+                }
+                continue;
+            }
+
+            let config = server.config();
+            if !begin_shutdown(
+                &server,
+                &format!("signal {}", signal),
+                config.shutdown_countdown.as_secs(),
+                &config.shutdown_message,
+            ) {
+                log_warn!("Signal {} during shutdown, exiting now", signal);
+                process::exit(1);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Take connections from one listener, each client gets its own thread
+fn accept_clients(listener: &Listener, server: &Arc<Server>) {
+    loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let id = server.next_client_id.fetch_add(1, Ordering::SeqCst) + 1;
+
+                // a slow handshake holds a thread, one host only gets a few at once.
+                // Behind a proxy the address is the proxy's, and its clients' are
+                // only known from the header.
+                let config = server.config();
+                let peer = stream.peer_addr().clone();
+                let limit = if config.expects_proxy_header(peer.ip()) {
+                    0
+                } else {
+                    config.max_handshakes_per_ip
+                };
+                match server.handshakes.start(id, &stream, limit) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let error_msg = "too many connections from your address. cannot connect\n";
+                        let reason =
+                            format!("too many handshakes from {} (max {})", peer.ip(), limit);
+                        reject_connection(
+                            id,
+                            &stream,
+                            &peer,
+                            error_msg,
+                            "handshake_limit",
+                            &reason,
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        log_warn!(conn = id; "Connection from {} failed: {}", peer, e);
+                        continue;
+                    }
+                }
+
+                // the handshake may block, so it runs on the client's own thread
+                let server_clone = Arc::clone(server);
+                thread::spawn(move || {
+                    if let Err(e) = admit_client(stream, id, &server_clone) {
+                        log_error!(conn = id; "Error admitting client: {}", e);
+                    }
+                    server_clone.handshakes.finish(id);
+                    // a slot may have freed up for the waiting queue
+                    server_clone.waiting.notify();
+                });
+            }
+            Err(e) => {
+                log_warn!("Connection failed on {}: {}", listener, e);
+            }
+        }
+    }
+}
+
+// Start handing the sessions to a new process in the background, false if a
+// shutdown or another handoff is already running
+fn begin_handoff(server: &Arc<Server>, initiator: &str) -> bool {
+    if server.shutting_down.load(Ordering::SeqCst)
+        || server.handing_off.swap(true, Ordering::SeqCst)
+    {
+        return false;
+    }
+
+    let server = Arc::clone(server);
+    let initiator = initiator.to_string();
+    thread::spawn(move || hand_off(&server, &initiator));
+    true
+}
+
+// Stop every client thread, start a new process on the same sockets and exit once
+// it has taken over. If anything goes wrong the sessions carry on here.
+fn hand_off(server: &Arc<Server>, initiator: &str) {
+    log_info!("Handing off to a new process, requested by {}", initiator);
+
+    let deadline = Instant::now() + HANDOFF_STOP_TIMEOUT;
+    let error = loop {
+        // exclusive, so no relay is halfway through a message when this process exits
+        let clients_lock = server.clients.write().unwrap();
+        let all_parked = {
+            let parked = server.parked.lock().unwrap();
+            clients_lock.values().all(|c| parked.contains_key(&c.id))
+        };
+
+        if all_parked {
+            // the lock stays held, nothing may change the sessions from here on
+            match start_successor(server, &clients_lock) {
+                Ok(pid) => {
+                    systemd::notify(&format!("MAINPID={}", pid));
+                    log_info!(
+                        "Handed {} sessions off to process {}",
+                        clients_lock.len(),
+                        pid
+                    );
+                    // a place in the queue or a handshake cannot be handed over,
+                    // they have to come back
+                    let reconnect = "server is restarting. please reconnect\n";
+                    let dismissed = server.waiting.dismiss_all(reconnect)
+                        + server.handshakes.dismiss_all(reconnect);
+                    if dismissed > 0 {
+                        log_info!(
+                            "Asked {} waiting or connecting clients to reconnect",
+                            dismissed
+                        );
+                    }
+                    for (name, result) in [
+                        ("audit log", server.audit.sync()),
+                        ("transcript", server.transcript.sync()),
+                        ("log file", logging::sync()),
+                    ] {
+                        if let Err(e) = result {
+                            log_error!("Error syncing the {}: {}", name, e);
+                        }
+                    }
+                    process::exit(0);
+                }
+                Err(e) => break e,
+            }
+        }
+        drop(clients_lock);
+
+        if Instant::now() >= deadline {
+            break io::Error::new(
+                io::ErrorKind::TimedOut,
+                "client threads did not stop in time",
+            );
+        }
+        thread::sleep(READ_POLL);
+    };
+    log_error!(
+        "Handoff failed, the sessions stay with this process: {}",
+        error
+    );
+
+    let clients_lock = server.clients.read().unwrap();
+    let mut parked = {
+        let mut parked = server.parked.lock().unwrap();
+        server.handing_off.store(false, Ordering::SeqCst);
+        mem::take(&mut *parked)
+    };
+    for client in clients_lock.values() {
+        let pending = match parked.remove(&client.id) {
+            Some(pending) => pending,
+            None => continue,
+        };
+        let result = client.stream.try_clone().and_then(|stream| {
+            resume_session(server, stream, client.id, client.nickname.clone(), pending)
+        });
+        if let Err(e) = result {
+            log_error!(conn = client.id; "Error resuming {}: {}", client.nickname, e);
+        }
+    }
+}
+
+// hand the listening socket and the sessions to a new process, returns its pid
+fn start_successor(server: &Server, clients: &HashMap<String, Client>) -> io::Result<u32> {
+    let parked = server.parked.lock().unwrap();
+    let mut sessions = Vec::new();
+    for client in clients.values() {
+        sessions.push(Session {
+            stream: client.stream.try_clone()?,
+            id: client.id,
+            nickname: client.nickname.clone(),
+            operator: client.operator,
+            pending: parked.get(&client.id).cloned().unwrap_or_default(),
+        });
+    }
+    drop(parked);
+
+    let moderation = server.moderation.lock().unwrap();
+    let reports = server.reports.lock().unwrap();
+    let state = handoff::State {
+        listeners: server
+            .listeners
+            .iter()
+            .map(|(address, listener)| Ok((address.clone(), listener.try_clone()?)))
+            .collect::<io::Result<_>>()?,
+        socket_activated: server.socket_activated,
+        next_id: server.next_client_id.load(Ordering::SeqCst),
+        sessions,
+        muted: moderation.muted(),
+        shadow_banned: moderation.shadow_banned(),
+        reports: reports.all().cloned().collect(),
+        next_report_id: reports.next_id(),
+    };
+    drop(reports);
+    drop(moderation);
+
+    handoff::start_successor(&state)
+}
+
+// Keep what a client thread has read but not handled, so the new process can
+// handle it. False if no handoff is running after all.
+fn park_reader(
+    server: &Server,
+    id: u64,
+    partial: &[u8],
+    skipping: bool,
+    input: &io::Chain<&[u8], BufReader<Stream>>,
+) -> bool {
+    let mut parked = server.parked.lock().unwrap();
+    if !server.handing_off.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let (pending, reader) = input.get_ref();
+    // a frame being skipped has nothing worth keeping, only the flag goes on
+    let mut unread = if skipping {
+        Vec::new()
+    } else {
+        partial.to_vec()
+    };
+    unread.extend_from_slice(pending);
+    unread.extend_from_slice(reader.buffer());
+    parked.insert(
+        id,
+        Unread {
+            input: unread,
+            skipping,
+        },
+    );
+    true
+}
+
+// Pick up the sessions a previous process handed over
+fn resume_handed_off(server: &Arc<Server>, state: handoff::State) -> io::Result<()> {
+    server.next_client_id.store(state.next_id, Ordering::SeqCst);
+    {
+        let mut moderation = server.moderation.lock().unwrap();
+        for (nickname, remaining) in &state.muted {
+            moderation.mute(nickname, *remaining);
+        }
+        for nickname in &state.shadow_banned {
+            moderation.set_shadow_ban(nickname, true);
+        }
+    }
+    *server.reports.lock().unwrap() = ReportQueue::restore(state.reports, state.next_report_id);
+
+    let mut clients_lock = server.clients.write().unwrap();
+    for session in state.sessions {
+        session.stream.set_read_timeout(Some(READ_POLL))?;
+        if !session.stream.peer_connected() {
+            log_info!(conn = session.id; "{} left during the handoff", session.nickname);
+            continue;
+        }
+
+        let mut client = Client::new(
+            session.id,
+            session.nickname.clone(),
+            session.stream.try_clone()?,
+        );
+        client.operator = session.operator;
+        clients_lock.insert(session.nickname.clone(), client);
+        resume_session(
+            server,
+            session.stream,
+            session.id,
+            session.nickname,
+            session.pending,
+        )?;
+    }
+    log_info!(
+        "Resumed {} sessions from the previous process",
+        clients_lock.len()
+    );
+    Ok(())
+}
+
+// run a session that is already in the room on a new thread
+fn resume_session(
+    server: &Arc<Server>,
+    stream: Stream,
+    id: u64,
     nickname: String,
-    clients: Arc<Mutex<HashMap<String, Client>>>,
+    pending: Unread,
 ) -> io::Result<()> {
-    let client_addr = stream.peer_addr()?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let server = Arc::clone(server);
+    thread::spawn(move || {
+        if let Err(e) = handle_client(
+            stream,
+            reader,
+            pending,
+            id,
+            nickname.clone(),
+            Arc::clone(&server),
+        ) {
+            log_error!(conn = id; "Error handling client {}: {}", nickname, e);
+        }
+        server.waiting.notify();
+    });
+    Ok(())
+}
+
+// retry a bind while a previous process still holds the port, as after a handoff
+fn bind_retrying(retry: bool, bind: impl Fn() -> io::Result<()>) -> io::Result<()> {
+    let deadline = Instant::now() + HANDOFF_BIND_TIMEOUT;
+    loop {
+        match bind() {
+            Err(e)
+                if retry && e.kind() == io::ErrorKind::AddrInUse && Instant::now() < deadline =>
+            {
+                thread::sleep(READ_POLL)
+            }
+            result => return result,
+        }
+    }
+}
+
+// Greet a client that just entered the room and tell the others
+fn welcome_client(stream: &Stream, id: u64, nickname: &str, server: &Server) -> io::Result<()> {
+    let clients = &server.clients;
+    let client_addr = stream.peer_addr().clone();
 
     // print client connection information
     {
-        let clients_lock = clients.lock().unwrap();
+        let clients_lock = clients.read().unwrap();
         let num_users = clients_lock.len();
-        println!(
+        log_info!(
+            conn = id;
             "{} joined from {}:{}. There are {} users in the room",
             nickname,
             client_addr.ip(),
@@ -138,22 +1909,29 @@ fn handle_client(
         );
     }
 
+    // advertise the filter policy so the client can warn before sending
+    send_to_member(server, nickname, &policy_frame(&server.config()))?;
+
     // send welcome message to the new client
     {
-        let num_users = clients.lock().unwrap().len();
+        let num_users = clients.read().unwrap().len();
         let welcome_msg = format!(
-            "[Welcome {} to CAU net-class chat room at nsl5.cau.ac.kr:{}. There are {} users in the room.]\n",
-            nickname, PORT, num_users
+            "[Welcome {} to CAU net-class chat room. There are {} users in the room.]\n",
+            nickname, num_users
         );
 
-        let mut stream_clone = stream.try_clone()?;
-        stream_clone.write_all(welcome_msg.as_bytes())?;
-        stream_clone.flush()?;
+        send_to_member(server, nickname, &welcome_msg)?;
+
+        let motd = &server.config().motd;
+        if !motd.is_empty() {
+            let motd = format!("[Message of the day: {}]\n", motd);
+            send_to_member(server, nickname, &motd)?;
+        }
     }
 
     // broadcast to all clients that a new user has joined
     {
-        let clients_lock = clients.lock().unwrap();
+        let clients_lock = clients.read().unwrap();
         let num_users = clients_lock.len();
         let join_msg = format!(
             "[{} joined from {}:{}. There are {} users in the room.]\n",
@@ -163,68 +1941,239 @@ fn handle_client(
             num_users
         );
 
-        broadcast_to_all(&clients_lock, &join_msg, Some(&nickname));
+        announce(server, &clients_lock, &join_msg, Some(nickname));
     }
+    Ok(())
+}
 
-    // set up a buffered reader for the client stream
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut buffer = Vec::new();
+// Process incoming messages from clients, `pending` is input read before a handoff
+fn handle_client(
+    stream: Stream,
+    reader: BufReader<Stream>,
+    pending: Unread,
+    id: u64,
+    nickname: String,
+    server: Arc<Server>,
+) -> io::Result<()> {
+    let clients = &server.clients;
+    let mut input = pending.input.as_slice().chain(reader);
+    let mut max_frame = server.config().max_frame_bytes;
+    // the last read timed out in the middle of a frame
+    let mut partial = pending.skipping;
+    // continue_frame knows a frame over the limit by a buffer one byte too long
+    let mut buffer = if pending.skipping {
+        vec![0; max_frame + 1]
+    } else {
+        Vec::new()
+    };
+    let mut violations = 0;
+    let mut oper_failures = 0;
+    let mut rate_config = server.config();
+    let mut rate_limiter = RateLimiter::new(&rate_config);
+    let mut spam_detector = SpamDetector::default();
 
     // main loop to read messages from the client
     loop {
-        buffer.clear();
-        let bytes_read = reader.read_until(b'\n', &mut buffer)?;
+        if server.handing_off.load(Ordering::SeqCst) {
+            let started = if partial { buffer.as_slice() } else { &[] };
+            let skipping = partial && buffer.len() > max_frame;
+            if park_reader(&server, id, started, skipping, &input) {
+                return Ok(());
+            }
+        }
 
-        // check for end of stream
-        if bytes_read == 0 {
-            break;
+        max_frame = server.config().max_frame_bytes;
+        let result = if partial {
+            frame::continue_frame(&mut input, &mut buffer, max_frame)
+        } else {
+            frame::read_frame(&mut input, &mut buffer, max_frame)
+        };
+        partial = false;
+        match result {
+            Ok(Frame::Line) => METRICS.bytes_in(buffer.len() + 1),
+            Ok(Frame::TooLong) => {
+                let error_msg = format!(
+                    "Error: Message too long (max {} bytes), it was not sent.\n",
+                    max_frame
+                );
+                if reject_invalid_frame(&stream, &nickname, &server, &mut violations, &error_msg)? {
+                    return Ok(());
+                }
+                continue;
+            }
+            // check for end of stream
+            Ok(Frame::Eof) => break,
+            // the poll timeout, what arrived of a frame so far stays in the buffer
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                partial = true;
+                continue;
+            }
+            // a reset connection leaves the room like a closed one
+            Err(e) => {
+                log_debug!(conn = id; "Read from {} failed: {}", nickname, e);
+                break;
+            }
         }
 
-        // skip empty messages or messages with only the command byte
-        if buffer.is_empty() || bytes_read <= 1 {
+        // skip empty messages
+        if buffer.is_empty() {
             continue;
         }
 
         // first byte is the command, rest is the content
         let cmd = buffer[0];
-        let content = String::from_utf8_lossy(&buffer[1..bytes_read - 1]).to_string();
+        METRICS.command(command_name(cmd));
 
-        // debug print the received message
-        println!(
-            "Received from {}: cmd={}, content='{}', bytes={}",
-            nickname, cmd, content, bytes_read
-        );
+        // drop frames over the rate limits before doing any work for them
+        let class = match cmd {
+            CMD_CHAT | CMD_EXCEPT => RateClass::Chat,
+            CMD_TO => RateClass::To,
+            CMD_LIST => RateClass::List,
+            CMD_PING => RateClass::Ping,
+            _ => RateClass::Other,
+        };
+        // a reload applies new limits from the next frame on
+        let config = server.config();
+        if !Arc::ptr_eq(&config, &rate_config) {
+            rate_limiter.reconfigure(&config);
+            rate_config = config;
+        }
+        match rate_limiter.check(class) {
+            RateDecision::Allow => {}
+            RateDecision::Throttle { notify } => {
+                if notify {
+                    if let Some(client) = clients.read().unwrap().get(&nickname) {
+                        let _ =
+                            client.send_message("Error: You are sending too fast. Slow down.\n");
+                    }
+                }
+                continue;
+            }
+            RateDecision::Kick => {
+                server.audit_automatic("kick", &stream, &nickname, "flooding");
+                disconnect_client(
+                    &nickname,
+                    &server,
+                    "You are flooding the room and will be disconnected.\n",
+                    "flooding",
+                )?;
+                return Ok(());
+            }
+        }
 
-        // Check for prohibited content regardless of command type
-        if contains_prohibited_content(&content) {
-            disconnect_for_prohibited_content(&stream, &nickname, &clients)?;
-            return Ok(());
+        let content = match std::str::from_utf8(&buffer[1..]) {
+            // control characters never make it into what others see
+            Ok(text) => sanitize::strip_control(text),
+            Err(_) => {
+                let error_msg = "Error: Message is not valid UTF-8, it was not sent.\n";
+                if reject_invalid_frame(&stream, &nickname, &server, &mut violations, error_msg)? {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+
+        // nothing left to say once the control characters are gone
+        if cmd == CMD_CHAT && content.trim().is_empty() {
+            continue;
+        }
+
+        // message text is redacted unless log_content is set, the operator password always is
+        if logging::enabled(LogLevel::Debug) {
+            log_debug!(
+                conn = id;
+                "Received from {}: cmd={}, content={}, bytes={}",
+                nickname,
+                command_name(cmd),
+                if cmd == CMD_OPER {
+                    "<hidden>".to_string()
+                } else {
+                    logging::content(&content)
+                },
+                buffer.len() + 1
+            );
+        }
+
+        // Check for prohibited content regardless of command type,
+        // the offending message is never delivered
+        if server.config().filter.matches(&content) {
+            if strike_for_offense(&stream, &nickname, &server, Offense::ProhibitedContent)? {
+                return Ok(());
+            }
+            continue;
+        }
+
+        if matches!(cmd, CMD_CHAT | CMD_TO | CMD_EXCEPT) {
+            // muted users cannot send messages to others
+            if reject_if_muted(&nickname, &server) {
+                continue;
+            }
+
+            // \to and \except carry "nickname message", only the message is checked
+            let message = match cmd {
+                CMD_CHAT => content.as_str(),
+                _ => content.split_once(' ').map_or("", |(_, message)| message),
+            };
+            // mentions are looked up in the registry as they are found
+            let offense = {
+                let clients_lock = clients.read().unwrap();
+                let is_user = |word: &str| word != nickname && clients_lock.contains_key(word);
+                spam_detector.check(message, is_user, &server.config())
+            };
+            if let Some(offense) = offense {
+                if act_on_spam(&stream, &nickname, &server, offense)? {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            if swallow_if_shadow_banned(cmd, &content, &nickname, &server) {
+                continue;
+            }
         }
 
         match cmd {
             CMD_CHAT => {
-                // print the chat message
-                println!("{}: {}", nickname, content);
-
                 // broadcast the message to all clients
                 {
-                    let clients_lock = clients.lock().unwrap();
-                    let msg = format!("{}> {}\n", nickname, content);
+                    let clients_lock = clients.read().unwrap();
+                    let msg = user_message(&format!("{}> {}", nickname, content));
                     broadcast_to_all(&clients_lock, &msg, Some(&nickname));
                 }
+                server
+                    .history
+                    .lock()
+                    .unwrap()
+                    .push(&nickname, None, &content);
+                server.transcript.message(&nickname, None, &content);
             }
             CMD_LIST => {
-                let clients_lock = clients.lock().unwrap();
+                let clients_lock = clients.read().unwrap();
                 let mut list_msg = String::new();
 
+                // operators also see who is shadow-banned
+                let is_operator = clients_lock.get(&nickname).is_some_and(|c| c.operator);
+                let moderation = server.moderation.lock().unwrap();
+
                 // message format
                 list_msg.push_str("Connected users:\n");
                 for (nick, client) in clients_lock.iter() {
+                    let marker = if is_operator && moderation.is_shadow_banned(nick) {
+                        " [shadow-banned]"
+                    } else {
+                        ""
+                    };
                     list_msg.push_str(&format!(
-                        "{} ({}), {}, {}\n",
-                        nick, client.nickname, client.ip, client.port
+                        "{} ({}), {}, {}{}\n",
+                        nick, client.nickname, client.ip, client.port, marker
                     ));
                 }
+                drop(moderation);
 
                 // send the message to the requesting client
                 if let Some(client) = clients_lock.get(&nickname) {
@@ -237,14 +2186,20 @@ fn handle_client(
                     let target = &content[..space_idx];
                     let message = &content[space_idx + 1..];
 
-                    let clients_lock = clients.lock().unwrap();
+                    let clients_lock = clients.read().unwrap();
 
                     if clients_lock.contains_key(target) {
                         // send the message to the target user
                         if let Some(client) = clients_lock.get(target) {
-                            let msg = format!("from: {}> {}\n", nickname, message);
+                            let msg = user_message(&format!("from: {}> {}", nickname, message));
                             let _ = client.send_message(&msg);
                         }
+                        server
+                            .history
+                            .lock()
+                            .unwrap()
+                            .push(&nickname, Some(target), message);
+                        server.transcript.direct(&nickname, target, message);
                     } else {
                         // error message if target user does not exist
                         if let Some(client) = clients_lock.get(&nickname) {
@@ -261,19 +2216,27 @@ fn handle_client(
 
                     if except_nick == nickname {
                         // can't except client itself
-                        println!("invalid command: \\except {} {}", except_nick, message);
-                        if let Some(client) = clients.lock().unwrap().get(&nickname) {
+                        log_debug!(conn = id; "invalid command: \\except to self");
+                        if let Some(client) = clients.read().unwrap().get(&nickname) {
                             let _ = client.send_message("invalid command\n");
                         }
                     } else {
-                        let clients_lock = clients.lock().unwrap();
+                        let clients_lock = clients.read().unwrap();
                         if clients_lock.contains_key(except_nick) {
                             for (nick, client) in clients_lock.iter() {
                                 if *nick != nickname && *nick != except_nick {
-                                    let msg = format!("{}> {}\n", nickname, message);
+                                    let msg = user_message(&format!("{}> {}", nickname, message));
                                     let _ = client.send_message(&msg);
                                 }
                             }
+                            server
+                                .history
+                                .lock()
+                                .unwrap()
+                                .push(&nickname, None, message);
+                            server
+                                .transcript
+                                .message(&nickname, Some(except_nick), message);
                         } else {
                             if let Some(client) = clients_lock.get(&nickname) {
                                 let error_msg =
@@ -284,58 +2247,47 @@ fn handle_client(
                     }
                 } else {
                     // invaild \except command
-                    println!("invalid command: \\except {}", content);
-                    if let Some(client) = clients.lock().unwrap().get(&nickname) {
+                    log_debug!(conn = id; "invalid command: \\except without a message");
+                    if let Some(client) = clients.read().unwrap().get(&nickname) {
                         let _ = client.send_message("invalid command\n");
                     }
                 }
             }
             CMD_BAN => {
                 let ban_nick = content.trim();
-                let mut clients_lock = clients.lock().unwrap();
-
-                if ban_nick != nickname && clients_lock.contains_key(ban_nick) {
-                    // ban the user
-                    if let Some(client) = clients_lock.get(ban_nick) {
-                        let ban_msg = format!("you are banned by {}\n", nickname);
-                        let _ = client.send_message(&ban_msg);
+                // checked under the read lock, only the ban itself writes
+                let error_msg = {
+                    let clients_lock = clients.read().unwrap();
+                    let is_operator = clients_lock.get(&nickname).is_some_and(|c| c.operator);
+                    if server.config().ban_requires_operator && !is_operator {
+                        // only operators may ban
+                        Some("Error: Only operators can ban users.\n".to_string())
+                    } else if ban_nick == nickname {
+                        // can't ban client itself
+                        Some("Error: You cannot ban yourself.\n".to_string())
+                    } else if !clients_lock.contains_key(ban_nick) {
+                        // no such user
+                        Some(format!("Error: User '{}' does not exist.\n", ban_nick))
+                    } else {
+                        None
                     }
+                };
 
-                    // remove the banned user from the client list
-                    clients_lock.remove(ban_nick);
-
-                    // broadcast the ban message to all clients
-                    let ban_broadcast = format!(
-                        "[{} left the room. There are {} users now]\n",
-                        ban_nick,
-                        clients_lock.len()
-                    );
-
-                    println!(
-                        "{} was banned by {}. There are {} users now",
-                        ban_nick,
-                        nickname,
-                        clients_lock.len()
-                    );
-
-                    broadcast_to_all(&clients_lock, &ban_broadcast, None);
-                } else if ban_nick == nickname {
-                    // can't ban client itself
-                    if let Some(client) = clients_lock.get(&nickname) {
-                        let error_msg = "Error: You cannot ban yourself.\n";
-                        let _ = client.send_message(error_msg);
+                match error_msg {
+                    Some(error_msg) => {
+                        let _ = send_to_member(&server, &nickname, &error_msg);
                     }
-                } else {
-                    // no such user
-                    if let Some(client) = clients_lock.get(&nickname) {
-                        let error_msg = format!("Error: User '{}' does not exist.\n", ban_nick);
-                        let _ = client.send_message(&error_msg);
+                    None => {
+                        let mut clients_lock = clients.write().unwrap();
+                        if clients_lock.contains_key(ban_nick) {
+                            ban_user(&server, &mut clients_lock, &nickname, ban_nick, "\\ban");
+                        }
                     }
                 }
             }
             CMD_PING => {
                 let start = Instant::now();
-                let clients_lock = clients.lock().unwrap();
+                let clients_lock = clients.read().unwrap();
 
                 if let Some(client) = clients_lock.get(&nickname) {
                     // send ping message
@@ -343,10 +2295,21 @@ fn handle_client(
                     let _ = client.send_message(&rtt_msg);
                 }
             }
+            CMD_OPER => {
+                if handle_oper(&stream, &nickname, &content, &server, &mut oper_failures) {
+                    return Ok(());
+                }
+            }
+            CMD_VOTEKICK => handle_votekick(&nickname, content.trim(), &server),
+            CMD_AUDIT => handle_audit(&nickname, &content, &server),
+            CMD_REPORT => handle_report(&nickname, content.trim(), &server),
+            CMD_REPORTS => handle_reports(&nickname, &content, &server),
+            CMD_SHADOWBAN => handle_shadowban(&nickname, &content, &server),
             CMD_EXIT => {
                 // disconnect the client
-                let mut clients_lock = clients.lock().unwrap();
+                let mut clients_lock = clients.write().unwrap();
                 clients_lock.remove(&nickname);
+                server.votes.lock().unwrap().cancel_for(&nickname);
 
                 let exit_msg = format!(
                     "[{} left the room. There are {} users now]\n",
@@ -354,28 +2317,37 @@ fn handle_client(
                     clients_lock.len()
                 );
 
-                println!(
+                log_info!(
+                    conn = id;
                     "{} left the room. There are {} users now",
                     nickname,
                     clients_lock.len()
                 );
 
                 // broadcast the exit message to all clients
-                broadcast_to_all(&clients_lock, &exit_msg, None);
+                announce(&server, &clients_lock, &exit_msg, None);
 
                 break;
             }
             _ => {
-                println!("Invalid command: {}", cmd);
+                log_debug!(conn = id; "Invalid command: {}", cmd);
             }
         }
     }
 
-    // disconnect the client
-    let mut clients_lock = clients.lock().unwrap();
+    // disconnect the client, unless it was already removed (exit, ban, kick)
+    let mut clients_lock = clients.write().unwrap();
+    if clients_lock
+        .get(&nickname)
+        .is_none_or(|client| client.id != id)
+    {
+        return Ok(());
+    }
     clients_lock.remove(&nickname);
+    server.votes.lock().unwrap().cancel_for(&nickname);
 
-    println!(
+    log_info!(
+        conn = id;
         "{} disconnected. There are {} users now",
         nickname,
         clients_lock.len()
@@ -388,100 +2360,164 @@ fn handle_client(
     );
 
     // broadcast the leave message to all clients
-    broadcast_to_all(&clients_lock, &leave_msg, None);
+    announce(&server, &clients_lock, &leave_msg, None);
 
     Ok(())
 }
 
 fn main() -> io::Result<()> {
-    // generate a random port number
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", PORT))?;
-    println!("Server listening on port {}", PORT);
+    // optional config file as the only argument
+    let args: Vec<String> = env::args().collect();
+    let config = match args.len() {
+        1 => Config::default(),
+        2 => Config::load(&args[1]).unwrap_or_else(|e| {
+            eprintln!("Invalid config: {}", e);
+            process::exit(1);
+        }),
+        _ => {
+            eprintln!("Usage: {} [config_file]", args[0]);
+            process::exit(1);
+        }
+    };
 
-    // save the clients in a thread-safe structure
-    let clients: Arc<Mutex<HashMap<String, Client>>> = Arc::new(Mutex::new(HashMap::new()));
+    logging::init(&config)?;
 
-    // accept incoming connections
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let client_addr = stream.peer_addr()?;
-                println!(
-                    "New connection from {}:{}",
-                    client_addr.ip(),
-                    client_addr.port()
-                );
+    // a previous process may be handing over its sockets and sessions, or systemd
+    // may have opened the sockets for us
+    let mut inherited = handoff::take_over()?;
+    let (mut listeners, socket_activated): (Vec<(String, Listener)>, bool) = match &mut inherited {
+        Some((state, _)) => {
+            log_info!(
+                "Taking over {} sessions from the previous process",
+                state.sessions.len()
+            );
+            (mem::take(&mut state.listeners), state.socket_activated)
+        }
+        None => {
+            let activated: Vec<_> = systemd::listen_fds()?
+                .into_iter()
+                .map(|listener| (listener.to_string(), listener))
+                .collect();
+            let socket_activated = !activated.is_empty();
+            (activated, socket_activated)
+        }
+    };
+    // a handoff keeps the old listeners and opens newly configured ones, socket
+    // activation decides on its own, also for every process it hands off to
+    if !socket_activated {
+        for address in &config.listen {
+            if listeners.iter().any(|(bound, _)| bound == address) {
+                continue;
+            }
+            let listener = Listener::bind(address)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", address, e)))?;
+            listeners.push((address.clone(), listener));
+        }
+    }
+    for (_, listener) in &listeners {
+        log_info!("Server listening on {}", listener);
+    }
+    log_info!("Filter policy version {:016x}", config.filter.version());
 
-                // check if the maximum number of clients is reached
-                let current_clients = clients.lock().unwrap().len();
-                if current_clients >= MAX_CLIENTS {
-                    let error_msg = "chatting room full. cannot connect\n";
-                    let mut stream_clone = stream.try_clone()?;
-                    stream_clone.write_all(error_msg.as_bytes())?;
-                    stream_clone.flush()?;
-                    println!(
-                        "Connection from {}:{} rejected: chatting room full (max {} clients)",
-                        client_addr.ip(),
-                        client_addr.port(),
-                        MAX_CLIENTS
-                    );
-                    continue;
-                }
+    // a process taking over from a daemon is in the background already
+    if config.daemon && inherited.is_none() {
+        if config.log_file.is_empty() {
+            log_warn!("Running as a daemon without log_file, the log is discarded");
+        }
+        daemon::daemonize()?;
+    }
+    if !config.pid_file.is_empty() {
+        daemon::write_pid_file(&config.pid_file)?;
+    }
 
-                // read the nickname from the client
-                let mut reader = BufReader::new(&stream);
-                let mut nickname = String::new();
-                reader.read_line(&mut nickname)?;
-                nickname = nickname.trim().to_string();
-
-                // check the nickname format
-                if nickname.len() > 10 || nickname.contains(|c: char| !c.is_ascii_alphanumeric()) {
-                    let error_msg = "nickname must be <= 10 characters, English only, no spaces or special chars\n";
-                    let mut stream_clone = stream.try_clone()?;
-                    stream_clone.write_all(error_msg.as_bytes())?;
-                    stream_clone.flush()?;
-                    println!(
-                        "Connection from {}:{} rejected: invalid nickname format '{}'",
-                        client_addr.ip(),
-                        client_addr.port(),
-                        nickname
-                    );
-                    continue;
-                }
+    let audit = AuditLog::open(
+        &config.audit_log_path,
+        config.audit_max_bytes,
+        config.audit_keep,
+    )?;
 
-                // check if the nickname is already in use
-                let mut clients_lock = clients.lock().unwrap();
-                if clients_lock.contains_key(&nickname) {
-                    let error_msg = "nickname already used by another user. cannot connect\n";
-                    let mut stream_clone = stream.try_clone()?;
-                    stream_clone.write_all(error_msg.as_bytes())?;
-                    stream_clone.flush()?;
-                    println!(
-                        "Connection from {}:{} rejected: nickname '{}' already in use",
-                        client_addr.ip(),
-                        client_addr.port(),
-                        nickname
-                    );
-                    continue;
-                }
+    let history = MessageBuffer::new(config.report_buffer_size);
+    let transcript = Transcript::open(&config)?;
 
-                // add the new client to the list
-                let client = Client::new(nickname.clone(), stream.try_clone()?);
-                clients_lock.insert(nickname.clone(), client);
-                drop(clients_lock);
+    // save the clients and moderation state in a thread-safe structure
+    let server = Arc::new(Server {
+        config: RwLock::new(Arc::new(config)),
+        config_path: args.get(1).cloned(),
+        started: Instant::now(),
+        clients: RwLock::new(HashMap::new()),
+        moderation: Mutex::new(Moderation::default()),
+        votes: Mutex::new(VoteKick::default()),
+        audit,
+        history: Mutex::new(history),
+        reports: Mutex::new(ReportQueue::default()),
+        waiting: WaitingQueue::default(),
+        handshakes: Handshakes::default(),
+        transcript,
+        shutting_down: AtomicBool::new(false),
+        listeners: listeners
+            .iter()
+            .map(|(address, listener)| Ok((address.clone(), listener.try_clone()?)))
+            .collect::<io::Result<_>>()?,
+        socket_activated,
+        next_client_id: AtomicU64::new(0),
+        handing_off: AtomicBool::new(false),
+        parked: Mutex::new(HashMap::new()),
+    });
 
-                // spawn a new thread to handle the client
-                let clients_clone = Arc::clone(&clients);
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, nickname.clone(), clients_clone) {
-                        eprintln!("Error handling client {}: {}", nickname, e);
-                    }
-                });
-            }
-            Err(e) => {
-                eprintln!("Connection failed: {}", e);
-            }
-        }
+    let handed_over = inherited.is_some();
+    if let Some((state, path)) = inherited {
+        resume_handed_off(&server, state)?;
+        // tells the previous process it can exit
+        fs::remove_file(path)?;
+    }
+
+    handle_signals(Arc::clone(&server))?;
+
+    // operator console on stdin
+    let console_server = Arc::clone(&server);
+    thread::spawn(move || console::run(console_server));
+
+    // metrics for scraping, off when no address is configured
+    let metrics_address = server.config().metrics_address.clone();
+    if !metrics_address.is_empty() {
+        bind_retrying(handed_over, || {
+            metrics::listen(Arc::clone(&server), &metrics_address)
+        })?;
+    }
+
+    // remote admin listener, needs a token to be useful
+    let admin_address = server.config().admin_address.clone();
+    if !server.config().admin_token.is_empty() && !admin_address.is_empty() {
+        bind_retrying(handed_over, || {
+            admin::listen(Arc::clone(&server), &admin_address)
+        })?;
+    }
+
+    // a registry that stays locked stops the pings, and systemd restarts the server,
+    // taking it for writing notices a stuck reader too
+    if let Some(interval) = systemd::watchdog_interval() {
+        let watchdog_server = Arc::clone(&server);
+        thread::spawn(move || loop {
+            drop(watchdog_server.clients.write().unwrap());
+            systemd::notify("WATCHDOG=1");
+            thread::sleep(interval);
+        });
+    }
+    systemd::notify(&format!(
+        "READY=1\nMAINPID={}\nSTATUS=Accepting connections",
+        process::id()
+    ));
+
+    // accept incoming connections, every listener feeds the same room
+    let accept_loops: Vec<_> = listeners
+        .into_iter()
+        .map(|(_, listener)| {
+            let server = Arc::clone(&server);
+            thread::spawn(move || accept_clients(&listener, &server))
+        })
+        .collect();
+    for accept_loop in accept_loops {
+        let _ = accept_loop.join();
     }
 
     Ok(())
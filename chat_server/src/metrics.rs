@@ -0,0 +1,358 @@
+// Server metrics in the Prometheus text format, served over a small HTTP listener
+// together with the health check at /healthz
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::health::Health;
+use crate::logging::{log_info, log_warn};
+use crate::Server;
+
+// upper bounds of the broadcast latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1, 1.0,
+];
+
+// how long a client gets to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LINE: usize = 1024;
+// all header lines together
+const MAX_HEADER_BYTES: usize = 8192;
+// requests served at once, connections beyond this are closed right away
+const MAX_CONNECTIONS: usize = 16;
+
+// Counters are process-wide, like in the usual client libraries, so any code
+// that writes to a socket can count its bytes without carrying the server around
+pub static METRICS: Metrics = Metrics::new();
+
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+pub struct Metrics {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    // frames received by command name
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    // connections turned away during the handshake, by reason
+    rejected: Mutex<BTreeMap<&'static str, u64>>,
+    // moderation actions by audit action name
+    moderation: Mutex<BTreeMap<String, u64>>,
+    broadcast_latency: Histogram,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            commands: Mutex::new(BTreeMap::new()),
+            rejected: Mutex::new(BTreeMap::new()),
+            moderation: Mutex::new(BTreeMap::new()),
+            broadcast_latency: Histogram {
+                buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+                sum_nanos: AtomicU64::new(0),
+                count: AtomicU64::new(0),
+            },
+        }
+    }
+
+    pub fn bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn command(&self, name: &'static str) {
+        *self.commands.lock().unwrap().entry(name).or_insert(0) += 1;
+    }
+
+    pub fn rejected(&self, reason: &'static str) {
+        *self.rejected.lock().unwrap().entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn moderation(&self, action: &str) {
+        let mut moderation = self.moderation.lock().unwrap();
+        match moderation.get_mut(action) {
+            Some(count) => *count += 1,
+            None => {
+                moderation.insert(action.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn broadcast_latency(&self, elapsed: Duration) {
+        let histogram = &self.broadcast_latency;
+        let seconds = elapsed.as_secs_f64();
+        // buckets are cumulative, as the format expects
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        histogram
+            .sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        histogram.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the whole exposition, with the gauges read from the live server state
+    pub fn render(&self, server: &Server) -> String {
+        let mut out = String::new();
+
+        let (users, operators) = {
+            let clients_lock = server.clients.read().unwrap();
+            let operators = clients_lock.values().filter(|c| c.operator).count();
+            (clients_lock.len(), operators)
+        };
+        gauge(
+            &mut out,
+            "chat_connected_users",
+            "Users in the room.",
+            users,
+        );
+        gauge(
+            &mut out,
+            "chat_operators",
+            "Connected operators.",
+            operators,
+        );
+        gauge(
+            &mut out,
+            "chat_waiting_clients",
+            "Clients in the waiting queue.",
+            server.waiting.len(),
+        );
+        gauge(
+            &mut out,
+            "chat_uptime_seconds",
+            "Seconds since the server started.",
+            server.started.elapsed().as_secs(),
+        );
+
+        counter(
+            &mut out,
+            "chat_received_bytes_total",
+            "Bytes received from clients.",
+            self.bytes_in.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "chat_sent_bytes_total",
+            "Bytes sent to clients.",
+            self.bytes_out.load(Ordering::Relaxed),
+        );
+
+        labeled_counter(
+            &mut out,
+            "chat_commands_total",
+            "Frames received by command.",
+            "command",
+            self.commands.lock().unwrap().iter().map(|(k, v)| (*k, *v)),
+        );
+        labeled_counter(
+            &mut out,
+            "chat_rejected_connections_total",
+            "Connections rejected during the handshake by reason.",
+            "reason",
+            self.rejected.lock().unwrap().iter().map(|(k, v)| (*k, *v)),
+        );
+        labeled_counter(
+            &mut out,
+            "chat_moderation_actions_total",
+            "Moderation actions by type.",
+            "action",
+            self.moderation
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (k.as_str(), *v)),
+        );
+
+        let histogram = &self.broadcast_latency;
+        out.push_str(
+            "# HELP chat_broadcast_duration_seconds Time to send one message to the whole room.\n",
+        );
+        out.push_str("# TYPE chat_broadcast_duration_seconds histogram\n");
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            out.push_str(&format!(
+                "chat_broadcast_duration_seconds_bucket{{le=\"{}\"}} {}\n",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            ));
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        out.push_str(&format!(
+            "chat_broadcast_duration_seconds_bucket{{le=\"+Inf\"}} {}\n",
+            count
+        ));
+        out.push_str(&format!(
+            "chat_broadcast_duration_seconds_sum {}\n",
+            histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9
+        ));
+        out.push_str(&format!(
+            "chat_broadcast_duration_seconds_count {}\n",
+            count
+        ));
+
+        out
+    }
+}
+
+fn gauge<T: std::fmt::Display>(out: &mut String, name: &str, help: &str, value: T) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+        name, help, name, name, value
+    ));
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+        name, help, name, name, value
+    ));
+}
+
+fn labeled_counter<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} counter\n",
+        name, help, name
+    ));
+    for (value, count) in values {
+        out.push_str(&format!("{}{{{}=\"{}\"}} {}\n", name, label, value, count));
+    }
+}
+
+// serve GET /metrics and /healthz on `address` until the process exits
+pub fn listen(server: Arc<Server>, address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let open = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log_warn!("Metrics connection failed: {}", e);
+                    continue;
+                }
+            };
+            // each request on its own thread, so a slow client cannot hold up a
+            // health probe, and only so many of them
+            if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                open.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            let server = Arc::clone(&server);
+            let open = Arc::clone(&open);
+            thread::spawn(move || {
+                if let Err(e) = serve(&server, stream) {
+                    log_warn!("Metrics request failed: {}", e);
+                }
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    log_info!("Metrics on http://{}/metrics", address);
+    Ok(())
+}
+
+// Reads that all end by one deadline, however slowly the bytes trickle in
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        (&mut self.stream).read(buf)
+    }
+}
+
+// one line of at most `limit` bytes, newline included
+fn read_limited_line<R: BufRead>(reader: &mut R, limit: usize) -> io::Result<String> {
+    let mut line = String::new();
+    reader.take(limit as u64).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line or header too long",
+        ));
+    }
+    Ok(line)
+}
+
+// answer one HTTP request
+fn serve(server: &Server, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(Deadline {
+        stream: &stream,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    });
+
+    let request_line = read_limited_line(&mut reader, MAX_REQUEST_LINE)?;
+    // skip the headers, nothing in them changes the answer
+    let mut header_bytes = 0;
+    loop {
+        let header = read_limited_line(&mut reader, MAX_HEADER_BYTES - header_bytes)?;
+        header_bytes += header.len();
+        if header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            METRICS.render(server),
+        ),
+        // the status code is what a probe looks at
+        (Some("GET"), Some("/healthz")) => {
+            let health = Health::check(server);
+            let status = if health.is_ok() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, "text/plain", health.line())
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "only GET is supported\n".to_string(),
+        ),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
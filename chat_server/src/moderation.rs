@@ -0,0 +1,121 @@
+// Strike bookkeeping for prohibited content and spam: warn first, then mute, then kick
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+// Why a message was blocked
+#[derive(Clone, Copy)]
+pub enum Offense {
+    ProhibitedContent,
+    RepeatedMessage,
+    MassMention,
+    Shouting,
+}
+
+impl Offense {
+    // completes "your message was blocked for ..."
+    pub fn describe(self) -> &'static str {
+        match self {
+            Offense::ProhibitedContent => "prohibited content",
+            Offense::RepeatedMessage => "repeating the same message",
+            Offense::MassMention => "mentioning too many users",
+            Offense::Shouting => "shouting in all caps",
+        }
+    }
+}
+
+// What the server does with a user who just received a strike
+pub enum StrikeAction {
+    Warn,
+    Mute(Duration),
+    Kick,
+}
+
+// strikes and mute state of one nickname
+#[derive(Default)]
+struct Standing {
+    strikes: Vec<Instant>,
+    muted_until: Option<Instant>,
+}
+
+// Kept per nickname rather than per connection so that reconnecting
+// does not wipe a user's strikes.
+#[derive(Default)]
+pub struct Moderation {
+    standings: HashMap<String, Standing>,
+    // their messages are accepted but never delivered to anyone
+    shadow_banned: HashSet<String>,
+}
+
+impl Moderation {
+    // record a strike and decide the action, returns the active strike count as well
+    pub fn add_strike(&mut self, nickname: &str, config: &Config) -> (u32, StrikeAction) {
+        let now = Instant::now();
+        let standing = self.standings.entry(nickname.to_string()).or_default();
+
+        // strikes older than the decay window no longer count
+        standing
+            .strikes
+            .retain(|at| now.duration_since(*at) < config.strike_decay);
+        standing.strikes.push(now);
+
+        let strikes = standing.strikes.len() as u32;
+        let action = if strikes >= config.strike_kick_threshold {
+            StrikeAction::Kick
+        } else if strikes >= config.strike_mute_threshold {
+            standing.muted_until = Some(now + config.mute_duration);
+            StrikeAction::Mute(config.mute_duration)
+        } else {
+            StrikeAction::Warn
+        };
+
+        (strikes, action)
+    }
+
+    // mute without a strike, e.g. as the configured spam action
+    pub fn mute(&mut self, nickname: &str, duration: Duration) {
+        let standing = self.standings.entry(nickname.to_string()).or_default();
+        standing.muted_until = Some(Instant::now() + duration);
+    }
+
+    // returns false if the state did not change
+    pub fn set_shadow_ban(&mut self, nickname: &str, banned: bool) -> bool {
+        if banned {
+            self.shadow_banned.insert(nickname.to_string())
+        } else {
+            self.shadow_banned.remove(nickname)
+        }
+    }
+
+    pub fn is_shadow_banned(&self, nickname: &str) -> bool {
+        self.shadow_banned.contains(nickname)
+    }
+
+    pub fn shadow_banned(&self) -> Vec<String> {
+        let mut nicknames: Vec<String> = self.shadow_banned.iter().cloned().collect();
+        nicknames.sort();
+        nicknames
+    }
+
+    // everyone still muted, with the time they have left
+    pub fn muted(&self) -> Vec<(String, Duration)> {
+        self.standings
+            .keys()
+            .filter_map(|nickname| Some((nickname.clone(), self.muted_for(nickname)?)))
+            .collect()
+    }
+
+    // remaining mute time, if the user is muted
+    pub fn muted_for(&self, nickname: &str) -> Option<Duration> {
+        let muted_until = self.standings.get(nickname)?.muted_until?;
+        let now = Instant::now();
+
+        if muted_until > now {
+            Some(muted_until - now)
+        } else {
+            None
+        }
+    }
+}
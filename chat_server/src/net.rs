@@ -0,0 +1,302 @@
+// Client connections over TCP (IPv4 or IPv6) or a Unix domain socket
+//
+// Listeners are configured as "host:port" or "unix:/path". "[::]:port" takes
+// IPv4 connections as well on a dual-stack host, so it replaces "0.0.0.0:port"
+// rather than going with it.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::FromStr;
+use std::time::Duration;
+
+// Where a connection comes from, as the room and the ban lists see it
+#[derive(Clone)]
+pub struct PeerAddr {
+    ip: String,
+    port: u16,
+}
+
+impl PeerAddr {
+    // everyone on the Unix socket shares one address
+    fn local() -> PeerAddr {
+        PeerAddr {
+            ip: "local".to_string(),
+            port: 0,
+        }
+    }
+
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> PeerAddr {
+        PeerAddr {
+            // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+            ip: addr.ip().to_canonical().to_string(),
+            port: addr.port(),
+        }
+    }
+}
+
+// "ip:port" as Display writes it
+impl FromStr for PeerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PeerAddr, String> {
+        let (ip, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("invalid address '{}'", s))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid port in '{}'", s))?;
+        Ok(PeerAddr {
+            ip: ip.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+pub struct Stream {
+    socket: Socket,
+    peer: PeerAddr,
+}
+
+impl Stream {
+    // adopt a connected socket inherited from another process, TCP or Unix
+    //
+    // SAFETY: `fd` must be an open socket that nothing else owns
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Stream> {
+        if socket_family(fd)? == libc::AF_UNIX {
+            let socket = UnixStream::from_raw_fd(fd);
+            Ok(Stream {
+                socket: Socket::Unix(socket),
+                peer: PeerAddr::local(),
+            })
+        } else {
+            let socket = TcpStream::from_raw_fd(fd);
+            let peer = socket.peer_addr()?.into();
+            Ok(Stream {
+                socket: Socket::Tcp(socket),
+                peer,
+            })
+        }
+    }
+
+    pub fn peer_addr(&self) -> &PeerAddr {
+        &self.peer
+    }
+
+    // the client's own address when the connection came through a proxy
+    pub fn set_peer_addr(&mut self, peer: PeerAddr) {
+        self.peer = peer;
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        let socket = match &self.socket {
+            Socket::Tcp(socket) => Socket::Tcp(socket.try_clone()?),
+            Socket::Unix(socket) => Socket::Unix(socket.try_clone()?),
+        };
+        Ok(Stream {
+            socket,
+            peer: self.peer.clone(),
+        })
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(socket) => socket.shutdown(how),
+            Socket::Unix(socket) => socket.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(socket) => socket.set_read_timeout(timeout),
+            Socket::Unix(socket) => socket.set_read_timeout(timeout),
+        }
+    }
+
+    // true while the peer has not closed the connection, without consuming its data
+    pub fn peer_connected(&self) -> bool {
+        let mut probe = 0u8;
+        // SAFETY: recv writes at most one byte into `probe`
+        let received = unsafe {
+            libc::recv(
+                self.as_raw_fd(),
+                (&mut probe as *mut u8).cast(),
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match received {
+            0 => false,
+            n if n > 0 => true,
+            _ => io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock,
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.socket {
+            Socket::Tcp(socket) => socket.as_raw_fd(),
+            Socket::Unix(socket) => socket.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.socket {
+            Socket::Tcp(socket) => socket.read(buf),
+            Socket::Unix(socket) => socket.read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.socket {
+            Socket::Tcp(socket) => (&*socket).write(buf),
+            Socket::Unix(socket) => (&*socket).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    // listen on "host:port" or "unix:/path"
+    pub fn bind(address: &str) -> io::Result<Listener> {
+        match address.strip_prefix("unix:") {
+            Some(path) => {
+                // a socket left behind by an earlier run would make bind fail, anything
+                // else at the path is left alone and bind reports it
+                if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.to_string()))
+            }
+            None => Ok(Listener::Tcp(TcpListener::bind(address)?)),
+        }
+    }
+
+    // adopt a listening socket inherited from another process or from systemd
+    //
+    // SAFETY: `fd` must be an open listening socket that nothing else owns
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Listener> {
+        if socket_family(fd)? == libc::AF_UNIX {
+            let listener = UnixListener::from_raw_fd(fd);
+            let path = listener
+                .local_addr()?
+                .as_pathname()
+                .map_or_else(String::new, |path| path.display().to_string());
+            Ok(Listener::Unix(listener, path))
+        } else {
+            Ok(Listener::Tcp(TcpListener::from_raw_fd(fd)))
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept()?;
+                Ok(Stream {
+                    socket: Socket::Tcp(socket),
+                    peer: addr.into(),
+                })
+            }
+            Listener::Unix(listener, _) => {
+                let (socket, _) = listener.accept()?;
+                Ok(Stream {
+                    socket: Socket::Unix(socket),
+                    peer: PeerAddr::local(),
+                })
+            }
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) => Ok(Listener::Tcp(listener.try_clone()?)),
+            Listener::Unix(listener, path) => {
+                Ok(Listener::Unix(listener.try_clone()?, path.clone()))
+            }
+        }
+    }
+
+    // the socket file of a Unix listener, for removing it on shutdown
+    pub fn unix_path(&self) -> Option<&str> {
+        match self {
+            Listener::Tcp(_) => None,
+            Listener::Unix(_, path) => Some(path),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Unix(_, path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    // SAFETY: getsockname fills at most `len` bytes of the zeroed storage
+    unsafe {
+        let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(
+            fd,
+            (&mut addr as *mut libc::sockaddr_storage).cast(),
+            &mut len,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(addr.ss_family as libc::c_int)
+    }
+}
@@ -0,0 +1,205 @@
+// PROXY protocol (v1 text and v2 binary) from a load balancer such as HAProxy
+//
+// The proxy sends one header before the client's own bytes, naming the address
+// the connection really comes from:
+//
+//   PROXY TCP4 203.0.113.7 10.0.0.1 50312 20417\r\n
+//
+// or the binary form starting with the 12 byte v2 signature.
+
+use std::io::{self, BufRead};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// a v1 header is at most 107 bytes including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+// Read the header, returns the client's address, or None when the proxy speaks
+// for itself (v1 UNKNOWN, v2 LOCAL) and the socket address stands
+pub fn read_header<R: BufRead>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    // the shortest v1 header is longer than the v2 signature
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start)?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(reader)
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(reader, &start)
+    } else {
+        Err(invalid("no PROXY header"))
+    }
+}
+
+fn read_v1<R: BufRead>(reader: &mut R, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY header too long"));
+        }
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {}
+        _ => return Err(invalid("malformed PROXY header")),
+    }
+
+    let ip: IpAddr = fields[2]
+        .parse()
+        .map_err(|_| invalid("bad source address in PROXY header"))?;
+    let port: u16 = fields[4]
+        .parse()
+        .map_err(|_| invalid("bad source port in PROXY header"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn read_v2<R: BufRead>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 4];
+    reader.read_exact(&mut head)?;
+    let (version_command, family) = (head[0], head[1]);
+    let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection, e.g. a health check
+        0 => return Ok(None),
+        // PROXY: relayed for a client
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    // addresses come first, TLVs after them are skipped
+    match family >> 4 {
+        // AF_INET: source, destination, source port, destination port
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6, the same with 16 byte addresses
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC, or an address family without an IP to show
+        0 | 3 => Ok(None),
+        _ => Err(invalid("malformed PROXY v2 addresses")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_header(&mut &header[..])
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let addr = parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 50312 20417\r\nrest").unwrap();
+        assert_eq!(addr, Some("203.0.113.7:50312".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let addr = parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 50312 20417\r\n").unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:50312".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn v1_too_long() {
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.resize(V1_MAX_LEN + 10, b'1');
+        header.extend_from_slice(b"\r\n");
+        assert!(parse(&header).is_err());
+    }
+
+    #[test]
+    fn v1_leaves_the_client_bytes() {
+        let mut input = &b"PROXY UNKNOWN\r\nalice\n"[..];
+        read_header(&mut input).unwrap();
+        assert_eq!(input, b"alice\n");
+    }
+
+    #[test]
+    fn v2_inet() {
+        let body = [203, 0, 113, 7, 10, 0, 0, 1, 0xc4, 0x88, 0x4f, 0xc1];
+        let addr = parse(&v2(1, 0x11, &body)).unwrap();
+        assert_eq!(addr, Some("203.0.113.7:50312".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_inet6() {
+        let mut body = vec![0u8; 36];
+        body[..16].copy_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        body[16..32].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body[32..34].copy_from_slice(&50312u16.to_be_bytes());
+        let addr = parse(&v2(1, 0x21, &body)).unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:50312".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local() {
+        assert_eq!(parse(&v2(0, 0x00, &[])).unwrap(), None);
+    }
+
+    #[test]
+    fn v2_unknown_command() {
+        assert!(parse(&v2(2, 0x11, &[0; 12])).is_err());
+    }
+
+    #[test]
+    fn v2_truncated_body() {
+        let mut header = v2(1, 0x11, &[0; 12]);
+        header.truncate(header.len() - 4);
+        assert_eq!(
+            parse(&header).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn v2_addresses_shorter_than_the_family() {
+        assert!(parse(&v2(1, 0x11, &[0; 8])).is_err());
+    }
+
+    #[test]
+    fn no_header() {
+        assert!(parse(b"alice\nhello there\n").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+// Token-bucket flood protection for one connection
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, RateLimit};
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // switch to a new limit, keeping what the connection has used up
+    fn set_limit(&mut self, limit: RateLimit) {
+        // a bucket that was unlimited starts full
+        if self.limit.burst == 0 {
+            self.tokens = limit.burst as f64;
+        }
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst as f64);
+    }
+
+    // add the tokens earned since the last refill
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_sec).min(self.limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    // whether a token can be taken, a burst of 0 disables the limit
+    fn has_token(&self) -> bool {
+        self.limit.burst == 0 || self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        if self.limit.burst > 0 {
+            self.tokens -= 1.0;
+        }
+    }
+}
+
+// Outcome of checking one frame against the limits
+pub enum RateDecision {
+    Allow,
+    // `notify` for the first throttled frame in the flood window, the rest are
+    // dropped quietly
+    Throttle { notify: bool },
+    Kick,
+}
+
+// Which per-command bucket a frame is charged to
+pub enum RateClass {
+    Chat,
+    To,
+    List,
+    Ping,
+    Other,
+}
+
+pub struct RateLimiter {
+    connection: TokenBucket,
+    chat: TokenBucket,
+    to: TokenBucket,
+    list: TokenBucket,
+    ping: TokenBucket,
+    // times of recently throttled frames, for escalation to kick
+    throttled: VecDeque<Instant>,
+    flood_kick_threshold: usize,
+    flood_window: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        RateLimiter {
+            connection: TokenBucket::new(config.rate_limit_connection),
+            chat: TokenBucket::new(config.rate_limit_chat),
+            to: TokenBucket::new(config.rate_limit_to),
+            list: TokenBucket::new(config.rate_limit_list),
+            ping: TokenBucket::new(config.rate_limit_ping),
+            throttled: VecDeque::new(),
+            flood_kick_threshold: config.flood_kick_threshold,
+            flood_window: config.flood_window,
+        }
+    }
+
+    // apply reloaded limits to a running connection
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.connection.set_limit(config.rate_limit_connection);
+        self.chat.set_limit(config.rate_limit_chat);
+        self.to.set_limit(config.rate_limit_to);
+        self.list.set_limit(config.rate_limit_list);
+        self.ping.set_limit(config.rate_limit_ping);
+        self.flood_kick_threshold = config.flood_kick_threshold;
+        self.flood_window = config.flood_window;
+    }
+
+    pub fn check(&mut self, class: RateClass) -> RateDecision {
+        let now = Instant::now();
+
+        let mut command_bucket = match class {
+            RateClass::Chat => Some(&mut self.chat),
+            RateClass::To => Some(&mut self.to),
+            RateClass::List => Some(&mut self.list),
+            RateClass::Ping => Some(&mut self.ping),
+            RateClass::Other => None,
+        };
+
+        // a frame one bucket refuses costs nothing from the other
+        self.connection.refill(now);
+        if let Some(bucket) = &mut command_bucket {
+            bucket.refill(now);
+        }
+        let command_ok = command_bucket
+            .as_ref()
+            .is_none_or(|bucket| bucket.has_token());
+        if command_ok && self.connection.has_token() {
+            if let Some(bucket) = command_bucket {
+                bucket.take();
+            }
+            self.connection.take();
+            return RateDecision::Allow;
+        }
+
+        // sustained abuse: too many throttled frames within the window
+        while let Some(at) = self.throttled.front() {
+            if now.duration_since(*at) < self.flood_window {
+                break;
+            }
+            self.throttled.pop_front();
+        }
+        let notify = self.throttled.is_empty();
+        self.throttled.push_back(now);
+
+        if self.flood_kick_threshold > 0 && self.throttled.len() >= self.flood_kick_threshold {
+            RateDecision::Kick
+        } else {
+            RateDecision::Throttle { notify }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // no refill, so only the burst counts
+    fn limit(burst: u32) -> RateLimit {
+        RateLimit {
+            burst,
+            per_sec: 0.0,
+        }
+    }
+
+    fn config(connection: u32, chat: u32, flood_kick_threshold: usize) -> Config {
+        Config {
+            rate_limit_connection: limit(connection),
+            rate_limit_chat: limit(chat),
+            rate_limit_ping: limit(0),
+            flood_kick_threshold,
+            flood_window: Duration::from_secs(60),
+            ..Config::default()
+        }
+    }
+
+    fn allowed(decision: RateDecision) -> bool {
+        matches!(decision, RateDecision::Allow)
+    }
+
+    #[test]
+    fn refused_frame_leaves_the_connection_bucket() {
+        let mut limiter = RateLimiter::new(&config(3, 1, 0));
+        assert!(allowed(limiter.check(RateClass::Chat)));
+        // the chat bucket is empty, the connection keeps its two tokens
+        assert!(!allowed(limiter.check(RateClass::Chat)));
+        assert!(allowed(limiter.check(RateClass::Ping)));
+        assert!(allowed(limiter.check(RateClass::Ping)));
+        assert!(!allowed(limiter.check(RateClass::Ping)));
+    }
+
+    #[test]
+    fn refused_frame_leaves_the_command_bucket() {
+        let mut limiter = RateLimiter::new(&config(1, 2, 0));
+        assert!(allowed(limiter.check(RateClass::Chat)));
+        assert!(!allowed(limiter.check(RateClass::Chat)));
+        // more room on the connection, the chat token refused above is still there
+        limiter.reconfigure(&config(0, 2, 0));
+        assert!(allowed(limiter.check(RateClass::Chat)));
+        assert!(!allowed(limiter.check(RateClass::Chat)));
+    }
+
+    #[test]
+    fn burst_of_zero_is_unlimited() {
+        let mut limiter = RateLimiter::new(&config(0, 0, 1));
+        for _ in 0..1000 {
+            assert!(allowed(limiter.check(RateClass::Chat)));
+        }
+    }
+
+    #[test]
+    fn one_notice_per_flood_window() {
+        let mut limiter = RateLimiter::new(&config(1, 0, 0));
+        assert!(allowed(limiter.check(RateClass::Other)));
+        assert!(matches!(
+            limiter.check(RateClass::Other),
+            RateDecision::Throttle { notify: true }
+        ));
+        for _ in 0..5 {
+            assert!(matches!(
+                limiter.check(RateClass::Other),
+                RateDecision::Throttle { notify: false }
+            ));
+        }
+    }
+
+    #[test]
+    fn kick_at_the_threshold() {
+        let mut limiter = RateLimiter::new(&config(1, 0, 3));
+        assert!(allowed(limiter.check(RateClass::Other)));
+        assert!(matches!(
+            limiter.check(RateClass::Other),
+            RateDecision::Throttle { .. }
+        ));
+        assert!(matches!(
+            limiter.check(RateClass::Other),
+            RateDecision::Throttle { .. }
+        ));
+        assert!(matches!(
+            limiter.check(RateClass::Other),
+            RateDecision::Kick
+        ));
+    }
+}
@@ -0,0 +1,230 @@
+// Recent message buffer and the user report queue operators work through
+
+use std::collections::VecDeque;
+
+use crate::config::Config;
+use crate::timestamp;
+
+// resolved reports kept for \reports show, the oldest go first
+const RESOLVED_KEPT: usize = 50;
+
+// A message as the server relayed it
+struct BufferedMessage {
+    at: u64,
+    sender: String,
+    // None for room messages, the recipient for \to
+    recipient: Option<String>,
+    text: String,
+}
+
+// The last messages relayed by the server, evidence for reports
+pub struct MessageBuffer {
+    messages: VecDeque<BufferedMessage>,
+    capacity: usize,
+}
+
+impl MessageBuffer {
+    pub fn new(capacity: usize) -> Self {
+        MessageBuffer {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, sender: &str, recipient: Option<&str>, text: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(BufferedMessage {
+            at: timestamp::unix_now(),
+            sender: sender.to_string(),
+            recipient: recipient.map(str::to_string),
+            text: text.to_string(),
+        });
+    }
+
+    // up to `limit` recent messages from `sender` that `reader` could see
+    pub fn recent_from(&self, sender: &str, reader: &str, limit: usize) -> Vec<String> {
+        let mut found: Vec<String> = self
+            .messages
+            .iter()
+            .rev()
+            .filter(|m| m.sender == sender)
+            .filter(|m| m.recipient.as_deref().is_none_or(|r| r == reader))
+            .take(limit)
+            .map(|m| match &m.recipient {
+                Some(recipient) => format!(
+                    "[{}] {} -> {}> {}",
+                    timestamp::format_utc(m.at),
+                    m.sender,
+                    recipient,
+                    m.text
+                ),
+                None => format!("[{}] {}> {}", timestamp::format_utc(m.at), m.sender, m.text),
+            })
+            .collect();
+        found.reverse();
+        found
+    }
+}
+
+// How an operator closed a report
+#[derive(Clone, Copy)]
+pub enum Resolution {
+    Dismiss,
+    Warn,
+    Mute,
+    Ban,
+}
+
+impl Resolution {
+    pub fn parse(value: &str) -> Option<Resolution> {
+        match value {
+            "dismiss" => Some(Resolution::Dismiss),
+            "warn" => Some(Resolution::Warn),
+            "mute" => Some(Resolution::Mute),
+            "ban" => Some(Resolution::Ban),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Resolution::Dismiss => "dismiss",
+            Resolution::Warn => "warn",
+            Resolution::Mute => "mute",
+            Resolution::Ban => "ban",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Report {
+    pub id: u64,
+    pub at: u64,
+    pub reporter: String,
+    pub target: String,
+    pub reason: String,
+    // the target's messages captured when the report was filed
+    pub messages: Vec<String>,
+    // resolution and the operator who chose it
+    pub resolved: Option<(Resolution, String)>,
+}
+
+impl Report {
+    // one line for the queue listing
+    pub fn summary(&self) -> String {
+        let status = match &self.resolved {
+            Some((resolution, by)) => format!("{} by {}", resolution.name(), by),
+            None => "open".to_string(),
+        };
+        format!(
+            "#{} [{}] {} reported by {} at {}: {} ({} messages)",
+            self.id,
+            status,
+            self.target,
+            self.reporter,
+            timestamp::format_utc(self.at),
+            self.reason,
+            self.messages.len()
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct ReportQueue {
+    reports: Vec<Report>,
+    next_id: u64,
+}
+
+impl ReportQueue {
+    // file a report, returns its id, or why the queue takes no more from `reporter`
+    pub fn file(
+        &mut self,
+        config: &Config,
+        reporter: &str,
+        target: &str,
+        reason: &str,
+        messages: Vec<String>,
+    ) -> Result<u64, String> {
+        let from_reporter = self
+            .open_reports()
+            .filter(|report| report.reporter == reporter)
+            .count();
+        if from_reporter >= config.report_max_open_per_user {
+            return Err(format!(
+                "You have {} open reports already, wait for the operators to review them.",
+                from_reporter
+            ));
+        }
+        if self.open_reports().count() >= config.report_max_open {
+            return Err("The report queue is full, try again later.".to_string());
+        }
+
+        self.next_id += 1;
+        self.reports.push(Report {
+            id: self.next_id,
+            at: timestamp::unix_now(),
+            reporter: reporter.to_string(),
+            target: target.to_string(),
+            reason: reason.to_string(),
+            messages,
+            resolved: None,
+        });
+        Ok(self.next_id)
+    }
+
+    // the queue as a previous process left it after a restart handoff
+    pub fn restore(reports: Vec<Report>, next_id: u64) -> ReportQueue {
+        ReportQueue { reports, next_id }
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &Report> {
+        self.reports.iter()
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    pub fn open_reports(&self) -> impl Iterator<Item = &Report> {
+        self.reports
+            .iter()
+            .filter(|report| report.resolved.is_none())
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Report> {
+        self.reports.iter().find(|report| report.id == id)
+    }
+
+    // close an open report, returns it so the caller can act on the target. Only
+    // the latest resolved reports stay in the queue.
+    pub fn resolve(&mut self, id: u64, resolution: Resolution, by: &str) -> Result<Report, String> {
+        let report = self
+            .reports
+            .iter_mut()
+            .find(|report| report.id == id)
+            .ok_or_else(|| format!("Report #{} does not exist.", id))?;
+
+        if report.resolved.is_some() {
+            return Err(format!("Report #{} is already resolved.", id));
+        }
+        report.resolved = Some((resolution, by.to_string()));
+        let report = report.clone();
+
+        // reports are filed in id order, so the first resolved ones are the oldest
+        let resolved = self.reports.iter().filter(|r| r.resolved.is_some()).count();
+        let mut evict = resolved.saturating_sub(RESOLVED_KEPT);
+        self.reports.retain(|r| {
+            let keep = evict == 0 || r.resolved.is_none();
+            if !keep {
+                evict -= 1;
+            }
+            keep
+        });
+        Ok(report)
+    }
+}
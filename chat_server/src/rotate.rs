@@ -0,0 +1,79 @@
+// Append-only files that rotate to path.1 ... path.N once they reach a size
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+
+pub struct RotatingFile {
+    path: String,
+    // 0 never rotates
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &str, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+        let (file, size) = open_append(path)?;
+        Ok(RotatingFile {
+            path: path.to_string(),
+            max_bytes,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // append a line, rotating once the file is full
+    pub fn write(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        if self.max_bytes > 0 && self.size >= self.max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    // push what was written to disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    // shift path -> path.1 -> path.2 ..., dropping the oldest, and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for idx in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, idx);
+                if fs::metadata(&from).is_ok() {
+                    fs::rename(&from, rotated_path(&self.path, idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        (self.file, self.size) = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+// path.N for the N-th rotated file, the live file for 0
+pub fn rotated_path(path: &str, idx: usize) -> String {
+    if idx == 0 {
+        path.to_string()
+    } else {
+        format!("{}.{}", path, idx)
+    }
+}
+
+fn open_append(path: &str) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
@@ -0,0 +1,17 @@
+// Keep user text from carrying terminal control sequences
+
+// Drop control characters (ANSI escapes, carriage returns, backspaces, ...) and
+// bidirectional overrides that could disguise a message, tabs become spaces
+pub fn strip_control(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c {
+            '\t' => Some(' '),
+            c if c.is_control() || is_bidi_control(c) => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
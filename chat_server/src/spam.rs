@@ -0,0 +1,220 @@
+// Spam heuristics over one sender's recent messages
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::moderation::Offense;
+
+// only this many characters are compared when looking for near-duplicates
+const COMPARE_LEN: usize = 200;
+
+// a message needs this many letters before it can count as shouting
+const MIN_CAPS_LETTERS: usize = 8;
+
+struct RecentMessage {
+    at: Instant,
+    normalized: String,
+    mentions: usize,
+    shouting: bool,
+}
+
+#[derive(Default)]
+pub struct SpamDetector {
+    recent: VecDeque<RecentMessage>,
+}
+
+impl SpamDetector {
+    // record a message and report the first heuristic it trips, if any, `is_user`
+    // tells whether a word names another connected user
+    pub fn check(
+        &mut self,
+        message: &str,
+        is_user: impl Fn(&str) -> bool,
+        config: &Config,
+    ) -> Option<Offense> {
+        let now = Instant::now();
+        while let Some(oldest) = self.recent.front() {
+            if now.duration_since(oldest.at) < config.spam_window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        let current = RecentMessage {
+            at: now,
+            normalized: normalize(message),
+            mentions: count_mentions(message, is_user),
+            shouting: is_shouting(message),
+        };
+
+        let repeats = 1 + self
+            .recent
+            .iter()
+            .filter(|m| similarity(&m.normalized, &current.normalized) >= config.spam_similarity)
+            .count();
+        let mentions = current.mentions + self.recent.iter().map(|m| m.mentions).sum::<usize>();
+        let shouts = self.recent.iter().filter(|m| m.shouting).count() + current.shouting as usize;
+        let shouting = current.shouting;
+
+        self.recent.push_back(current);
+
+        if config.spam_repeat_threshold > 0 && repeats >= config.spam_repeat_threshold {
+            Some(Offense::RepeatedMessage)
+        } else if config.spam_mention_threshold > 0 && mentions >= config.spam_mention_threshold {
+            Some(Offense::MassMention)
+        } else if config.spam_caps_threshold > 0 && shouting && shouts >= config.spam_caps_threshold
+        {
+            Some(Offense::Shouting)
+        } else {
+            None
+        }
+    }
+}
+
+// lowercase words separated by single spaces, punctuation dropped
+fn normalize(message: &str) -> String {
+    let cleaned: String = message
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .to_lowercase();
+    let normalized = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    // messages made of punctuation only are compared as typed
+    if normalized.is_empty() {
+        message.trim().to_string()
+    } else {
+        normalized
+    }
+}
+
+// 1.0 for identical strings, based on the edit distance of their prefixes
+fn similarity(a: &str, b: &str) -> f64 {
+    if a == b {
+        return 1.0;
+    }
+
+    let a: Vec<char> = a.chars().take(COMPARE_LEN).collect();
+    let b: Vec<char> = b.chars().take(COMPARE_LEN).collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    // Levenshtein distance with a single row
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    1.0 - row[b.len()] as f64 / longest as f64
+}
+
+// words naming a connected user, with or without a leading '@'
+fn count_mentions(message: &str, is_user: impl Fn(&str) -> bool) -> usize {
+    message
+        .split_whitespace()
+        .map(|word| word.trim_start_matches('@'))
+        .map(|word| word.trim_end_matches(|c: char| !c.is_ascii_alphanumeric()))
+        .filter(|word| is_user(word))
+        .count()
+}
+
+// mostly upper-case letters
+fn is_shouting(message: &str) -> bool {
+    let letters = message.chars().filter(|c| c.is_alphabetic()).count();
+    let upper = message.chars().filter(|c| c.is_uppercase()).count();
+
+    letters >= MIN_CAPS_LETTERS && upper * 10 >= letters * 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // only the heuristic under test is on
+    fn config(repeats: usize, mentions: usize, caps: usize) -> Config {
+        Config {
+            spam_repeat_threshold: repeats,
+            spam_mention_threshold: mentions,
+            spam_caps_threshold: caps,
+            ..Config::default()
+        }
+    }
+
+    fn is_user(word: &str) -> bool {
+        ["alice", "bob", "carol"].contains(&word)
+    }
+
+    #[test]
+    fn mentions_at_the_threshold() {
+        let config = config(0, 3, 0);
+        let mut detector = SpamDetector::default();
+        assert!(detector
+            .check("hi @alice and bob", is_user, &config)
+            .is_none());
+
+        let mut detector = SpamDetector::default();
+        assert!(matches!(
+            detector.check("hi @alice, bob and carol", is_user, &config),
+            Some(Offense::MassMention)
+        ));
+    }
+
+    #[test]
+    fn mentions_add_up_over_the_window() {
+        let config = config(0, 3, 0);
+        let mut detector = SpamDetector::default();
+        assert!(detector.check("alice bob", is_user, &config).is_none());
+        assert!(matches!(
+            detector.check("carol?", is_user, &config),
+            Some(Offense::MassMention)
+        ));
+    }
+
+    #[test]
+    fn repeats_at_the_threshold() {
+        let config = config(3, 0, 0);
+        let mut detector = SpamDetector::default();
+        assert!(detector.check("buy now", is_user, &config).is_none());
+        assert!(detector.check("Buy now!", is_user, &config).is_none());
+        assert!(matches!(
+            detector.check("buy  now", is_user, &config),
+            Some(Offense::RepeatedMessage)
+        ));
+    }
+
+    #[test]
+    fn different_messages_are_not_repeats() {
+        let config = config(2, 0, 0);
+        let mut detector = SpamDetector::default();
+        assert!(detector.check("good morning", is_user, &config).is_none());
+        assert!(detector.check("see you later", is_user, &config).is_none());
+    }
+
+    #[test]
+    fn shouting_at_the_threshold() {
+        let config = config(0, 0, 2);
+        let mut detector = SpamDetector::default();
+        assert!(detector.check("HELLO EVERYONE", is_user, &config).is_none());
+        assert!(matches!(
+            detector.check("WHERE IS EVERYBODY", is_user, &config),
+            Some(Offense::Shouting)
+        ));
+    }
+
+    #[test]
+    fn short_capitals_are_not_shouting() {
+        let config = config(0, 0, 1);
+        let mut detector = SpamDetector::default();
+        assert!(detector.check("OK", is_user, &config).is_none());
+        assert!(detector.check("Hello Everyone", is_user, &config).is_none());
+    }
+}
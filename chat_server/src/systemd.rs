@@ -0,0 +1,90 @@
+// Running under systemd: socket activation, readiness notification and the watchdog
+//
+// With a .socket unit the listening sockets arrive already open from fd 3 on
+// (LISTEN_FDS / LISTEN_PID) and replace the configured ones. With Type=notify
+// the server reports READY=1 once it accepts connections, RELOADING=1 around a
+// SIGHUP reload and STOPPING=1 on shutdown. WatchdogSec= makes it ping WATCHDOG=1 while the client registry
+// can still be locked. A restart handoff moves MAINPID to the new process, whose
+// own notifications need NotifyAccess=all.
+
+use std::env;
+use std::io;
+use std::os::fd::RawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+
+use crate::handoff;
+use crate::logging::log_warn;
+use crate::net::Listener;
+
+// the first descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+// Listening sockets passed by socket activation, empty when started any other way
+pub fn listen_fds() -> io::Result<Vec<Listener>> {
+    if !for_this_process("LISTEN_PID") {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()) {
+        Some(count) => count,
+        None => return Ok(Vec::new()),
+    };
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // they come without close-on-exec, a handoff passes its own copies on
+            handoff::set_inherited(fd, false)?;
+            // SAFETY: systemd opened these for this process and nothing else owns them
+            unsafe { Listener::from_raw_fd(fd) }
+        })
+        .collect()
+}
+
+// Send a state change to the service manager, nothing happens outside of systemd
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return,
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        // a leading '@' names a socket in the abstract namespace
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(e) = result {
+        log_warn!("Error notifying systemd at {}: {}", path, e);
+    }
+}
+
+// RELOADING=1 needs the time the reload started on the monotonic clock
+pub fn notify_reloading() {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: clock_gettime only writes to the timespec it is given
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+}
+
+// How often to ping the watchdog, None when it is not enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    // WATCHDOG_PID is optional, but when set it has to be us
+    if env::var_os("WATCHDOG_PID").is_some() && !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // half the timeout leaves room for a late ping
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+fn for_this_process(var: &str) -> bool {
+    env::var(var).ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(process::id())
+}
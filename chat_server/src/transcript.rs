@@ -0,0 +1,157 @@
+// Room transcripts: what the room saw, one file per room and UTC day
+//
+//   2026-01-01T12:00:00Z <alice> hello
+//   2026-01-01T12:00:05Z <alice -> bob> see you later
+//   2026-01-01T12:00:09Z * [bob left the room. There are 1 users now]
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::health::LastError;
+use crate::logging::log_warn;
+use crate::timestamp;
+
+// the server has a single room
+const ROOM: &str = "main";
+
+struct DayFile {
+    date: String,
+    file: File,
+}
+
+pub struct Transcript {
+    // empty when transcripts are off
+    dir: String,
+    // days of files kept, 0 keeps them all
+    retention_days: u64,
+    include_direct: bool,
+    current: Mutex<Option<DayFile>>,
+    last_error: LastError,
+}
+
+impl Transcript {
+    pub fn open(config: &Config) -> io::Result<Transcript> {
+        if !config.transcript_dir.is_empty() {
+            fs::create_dir_all(&config.transcript_dir)?;
+        }
+
+        Ok(Transcript {
+            dir: config.transcript_dir.clone(),
+            retention_days: config.transcript_retention_days,
+            include_direct: config.transcript_direct_messages,
+            current: Mutex::new(None),
+            last_error: LastError::new(),
+        })
+    }
+
+    // a message to the room, `except` is the user \except left out
+    pub fn message(&self, sender: &str, except: Option<&str>, text: &str) {
+        match except {
+            Some(except) => self.write(&format!("<{}> (except {}) {}", sender, except, text)),
+            None => self.write(&format!("<{}> {}", sender, text)),
+        }
+    }
+
+    // a \to message, only kept when direct messages are configured in
+    pub fn direct(&self, sender: &str, recipient: &str, text: &str) {
+        if self.include_direct {
+            self.write(&format!("<{} -> {}> {}", sender, recipient, text));
+        }
+    }
+
+    // a server notice to the room: joins, leaves, kicks, ...
+    pub fn event(&self, notice: &str) {
+        self.write(&format!("* {}", notice.trim_end()));
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        let result = match self.current.lock().unwrap().as_ref() {
+            Some(day) => day.file.sync_all(),
+            None => Ok(()),
+        };
+        self.last_error.record(&result);
+        result
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.get()
+    }
+
+    fn write(&self, entry: &str) {
+        if self.dir.is_empty() {
+            return;
+        }
+
+        let now = timestamp::unix_now();
+        let date = day_of(now);
+        let mut current = self.current.lock().unwrap();
+
+        // start the next day's file, and let the oldest ones go
+        if current.as_ref().is_none_or(|day| day.date != date) {
+            let path = Path::new(&self.dir).join(format!("{}-{}.log", ROOM, date));
+            let opened = OpenOptions::new().create(true).append(true).open(&path);
+            self.last_error.record(&opened);
+            match opened {
+                Ok(file) => *current = Some(DayFile { date, file }),
+                Err(e) => {
+                    log_warn!("Error opening transcript {}: {}", path.display(), e);
+                    return;
+                }
+            }
+            self.prune(now);
+        }
+
+        if let Some(day) = current.as_mut() {
+            let line = format!("{} {}\n", timestamp::format_utc(now), entry);
+            let result = day.file.write_all(line.as_bytes());
+            self.last_error.record(&result);
+            if let Err(e) = result {
+                log_warn!("Error writing transcript: {}", e);
+            }
+        }
+    }
+
+    // remove this room's files older than the retention period
+    fn prune(&self, now: u64) {
+        if self.retention_days == 0 {
+            return;
+        }
+        let cutoff = day_of(now.saturating_sub(self.retention_days * 86_400));
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log_warn!("Error listing transcripts in {}: {}", self.dir, e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let date = match name
+                .to_str()
+                .and_then(|name| name.strip_prefix(ROOM))
+                .and_then(|rest| rest.strip_prefix('-'))
+                .and_then(|rest| rest.strip_suffix(".log"))
+            {
+                Some(date) => date,
+                None => continue,
+            };
+
+            // YYYY-MM-DD compares like the dates themselves
+            if date.len() == 10 && date < cutoff.as_str() {
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    log_warn!("Error removing old transcript {:?}: {}", name, e);
+                }
+            }
+        }
+    }
+}
+
+// "2024-05-01"
+fn day_of(unix_secs: u64) -> String {
+    let (year, month, day) = timestamp::civil_date(unix_secs);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
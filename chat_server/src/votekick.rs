@@ -0,0 +1,240 @@
+// Room vote to kick a user when no operator is around
+
+use std::collections::HashSet;
+
+struct Vote {
+    id: u64,
+    target: String,
+    voters: HashSet<String>,
+    // fixed when the vote opens, so the room changing size does not move it
+    needed: usize,
+}
+
+// What a \votekick did
+pub enum VoteOutcome {
+    // a new vote was opened with the caller's vote
+    Opened {
+        id: u64,
+        votes: usize,
+        needed: usize,
+    },
+    Counted {
+        votes: usize,
+        needed: usize,
+    },
+    Passed {
+        needed: usize,
+    },
+    AlreadyVoted,
+    // fewer users than the vote needs could vote, so none was opened
+    TooFewVoters {
+        needed: usize,
+    },
+    // only one vote runs at a time, this names its target
+    Busy(String),
+}
+
+// How many votes kick a user out of a room where `eligible` users can vote
+pub fn needed_votes(eligible: usize, fraction: f64, min_votes: usize) -> usize {
+    ((eligible as f64 * fraction).ceil() as usize).max(min_votes)
+}
+
+// At most one vote at a time
+#[derive(Default)]
+pub struct VoteKick {
+    active: Option<Vote>,
+    next_id: u64,
+}
+
+impl VoteKick {
+    // cast a vote, `needed` is how many votes kick the target if this opens the vote
+    // and `eligible` how many users could vote
+    pub fn vote(
+        &mut self,
+        voter: &str,
+        target: &str,
+        eligible: usize,
+        needed: usize,
+    ) -> VoteOutcome {
+        let vote = match &mut self.active {
+            Some(vote) if vote.target != target => return VoteOutcome::Busy(vote.target.clone()),
+            Some(vote) => {
+                if !vote.voters.insert(voter.to_string()) {
+                    return VoteOutcome::AlreadyVoted;
+                }
+                vote
+            }
+            None if eligible < needed => return VoteOutcome::TooFewVoters { needed },
+            None => {
+                self.next_id += 1;
+                self.active.insert(Vote {
+                    id: self.next_id,
+                    target: target.to_string(),
+                    voters: HashSet::from([voter.to_string()]),
+                    needed,
+                })
+            }
+        };
+
+        let votes = vote.voters.len();
+        let opened = votes == 1;
+        let id = vote.id;
+        let needed = vote.needed;
+
+        if votes >= needed {
+            self.active = None;
+            VoteOutcome::Passed { needed }
+        } else if opened {
+            VoteOutcome::Opened { id, votes, needed }
+        } else {
+            VoteOutcome::Counted { votes, needed }
+        }
+    }
+
+    // close a vote that ran out of time, returns its target if it was still open
+    pub fn expire(&mut self, id: u64) -> Option<String> {
+        match &self.active {
+            Some(vote) if vote.id == id => self.active.take().map(|vote| vote.target),
+            _ => None,
+        }
+    }
+
+    // someone left the room: their vote no longer counts, and the vote ends if they
+    // were its target or its last voter
+    pub fn cancel_for(&mut self, nickname: &str) {
+        if let Some(vote) = &mut self.active {
+            vote.voters.remove(nickname);
+            if vote.target == nickname || vote.voters.is_empty() {
+                self.active = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn too_few_voters_for_the_minimum() {
+        // bob and alice alone, two votes needed by default
+        let needed = needed_votes(1, 0.5, 2);
+        assert_eq!(needed, 2);
+        let mut votes = VoteKick::default();
+        assert!(matches!(
+            votes.vote("bob", "alice", 1, needed),
+            VoteOutcome::TooFewVoters { needed: 2 }
+        ));
+        // no vote was left open
+        assert!(votes.expire(1).is_none());
+    }
+
+    #[test]
+    fn needed_votes_rounds_up_to_the_minimum() {
+        assert_eq!(needed_votes(5, 0.5, 2), 3);
+        assert_eq!(needed_votes(2, 0.5, 2), 2);
+        assert_eq!(needed_votes(4, 1.0, 2), 4);
+    }
+
+    #[test]
+    fn votes_are_counted_until_the_vote_passes() {
+        let mut votes = VoteKick::default();
+        assert!(matches!(
+            votes.vote("alice", "mallory", 4, 3),
+            VoteOutcome::Opened {
+                votes: 1,
+                needed: 3,
+                ..
+            }
+        ));
+        assert!(matches!(
+            votes.vote("alice", "mallory", 4, 3),
+            VoteOutcome::AlreadyVoted
+        ));
+        assert!(matches!(
+            votes.vote("bob", "mallory", 4, 3),
+            VoteOutcome::Counted {
+                votes: 2,
+                needed: 3
+            }
+        ));
+        assert!(matches!(
+            votes.vote("carol", "mallory", 4, 3),
+            VoteOutcome::Passed { needed: 3 }
+        ));
+        // the next vote opens afresh
+        assert!(matches!(
+            votes.vote("alice", "bob", 4, 3),
+            VoteOutcome::Opened { votes: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn one_vote_at_a_time() {
+        let mut votes = VoteKick::default();
+        votes.vote("alice", "mallory", 4, 3);
+        assert!(matches!(
+            votes.vote("bob", "carol", 4, 3),
+            VoteOutcome::Busy(target) if target == "mallory"
+        ));
+    }
+
+    #[test]
+    fn needed_is_fixed_when_the_vote_opens() {
+        let mut votes = VoteKick::default();
+        votes.vote("alice", "mallory", 5, 3);
+        // the room grew, later callers would ask for more
+        assert!(matches!(
+            votes.vote("bob", "mallory", 9, 5),
+            VoteOutcome::Counted {
+                votes: 2,
+                needed: 3
+            }
+        ));
+        assert!(matches!(
+            votes.vote("carol", "mallory", 9, 5),
+            VoteOutcome::Passed { needed: 3 }
+        ));
+    }
+
+    #[test]
+    fn votes_of_users_who_left_are_dropped() {
+        let mut votes = VoteKick::default();
+        votes.vote("alice", "mallory", 5, 3);
+        votes.vote("bob", "mallory", 5, 3);
+        votes.cancel_for("alice");
+        assert!(matches!(
+            votes.vote("carol", "mallory", 4, 3),
+            VoteOutcome::Counted {
+                votes: 2,
+                needed: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn vote_ends_when_its_target_or_last_voter_leaves() {
+        let mut votes = VoteKick::default();
+        let id = match votes.vote("alice", "mallory", 5, 3) {
+            VoteOutcome::Opened { id, .. } => id,
+            _ => unreachable!(),
+        };
+        votes.cancel_for("mallory");
+        assert!(votes.expire(id).is_none());
+
+        let id = match votes.vote("alice", "mallory", 5, 3) {
+            VoteOutcome::Opened { id, .. } => id,
+            _ => unreachable!(),
+        };
+        votes.cancel_for("alice");
+        assert!(votes.expire(id).is_none());
+    }
+
+    #[test]
+    fn expired_vote_names_its_target() {
+        let mut votes = VoteKick::default();
+        votes.vote("alice", "mallory", 5, 3);
+        assert_eq!(votes.expire(1).as_deref(), Some("mallory"));
+        assert!(votes.expire(1).is_none());
+    }
+}